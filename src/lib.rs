@@ -1,9 +1,22 @@
 
 extern crate libc;
+#[macro_use]
+extern crate lazy_static;
 
 use std::mem;
 use std::ptr;
 use std::ffi::{CString, CStr};
+use std::fs;
+use std::fmt;
+use std::io::{Read, Write};
+use std::process;
+use std::path::{Path, PathBuf};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use std::string;
 
@@ -17,10 +30,86 @@ mod ffi {
 }
 
 #[derive(Debug)]
-enum SQLite3Error {
-    OpenError,
-    StatementError,
-    ExecuteError,
+struct SQLite3Error {
+    code: i32,
+    extended_code: i32,
+    message: String,
+}
+
+impl SQLite3Error {
+    fn new(code: i32, message: String) -> SQLite3Error {
+        SQLite3Error {
+            code: code,
+            extended_code: code,
+            message: message,
+        }
+    }
+
+    // The last error that happened on the connection.
+    fn from_connection(db: *mut ffi::sqlite3) -> SQLite3Error {
+        unsafe {
+            SQLite3Error {
+                code: ffi::sqlite3_errcode(db),
+                extended_code: ffi::sqlite3_extended_errcode(db),
+                message: CStr::from_ptr(ffi::sqlite3_errmsg(db))
+                    .to_string_lossy()
+                    .into_owned(),
+            }
+        }
+    }
+
+    fn from_statement(stmt: &Statement) -> SQLite3Error {
+        SQLite3Error::from_connection(unsafe {
+            ffi::sqlite3_db_handle(stmt.stmt)
+        })
+    }
+}
+
+fn error_code_name(code: i32) -> &'static str {
+    match code & 0xff {
+        ffi::SQLITE_OK => "SQLITE_OK",
+        ffi::SQLITE_ERROR => "SQLITE_ERROR",
+        ffi::SQLITE_INTERNAL => "SQLITE_INTERNAL",
+        ffi::SQLITE_PERM => "SQLITE_PERM",
+        ffi::SQLITE_ABORT => "SQLITE_ABORT",
+        ffi::SQLITE_BUSY => "SQLITE_BUSY",
+        ffi::SQLITE_LOCKED => "SQLITE_LOCKED",
+        ffi::SQLITE_NOMEM => "SQLITE_NOMEM",
+        ffi::SQLITE_READONLY => "SQLITE_READONLY",
+        ffi::SQLITE_INTERRUPT => "SQLITE_INTERRUPT",
+        ffi::SQLITE_IOERR => "SQLITE_IOERR",
+        ffi::SQLITE_CORRUPT => "SQLITE_CORRUPT",
+        ffi::SQLITE_NOTFOUND => "SQLITE_NOTFOUND",
+        ffi::SQLITE_FULL => "SQLITE_FULL",
+        ffi::SQLITE_CANTOPEN => "SQLITE_CANTOPEN",
+        ffi::SQLITE_PROTOCOL => "SQLITE_PROTOCOL",
+        ffi::SQLITE_EMPTY => "SQLITE_EMPTY",
+        ffi::SQLITE_SCHEMA => "SQLITE_SCHEMA",
+        ffi::SQLITE_TOOBIG => "SQLITE_TOOBIG",
+        ffi::SQLITE_CONSTRAINT => "SQLITE_CONSTRAINT",
+        ffi::SQLITE_MISMATCH => "SQLITE_MISMATCH",
+        ffi::SQLITE_MISUSE => "SQLITE_MISUSE",
+        ffi::SQLITE_NOLFS => "SQLITE_NOLFS",
+        ffi::SQLITE_AUTH => "SQLITE_AUTH",
+        ffi::SQLITE_FORMAT => "SQLITE_FORMAT",
+        ffi::SQLITE_RANGE => "SQLITE_RANGE",
+        ffi::SQLITE_NOTADB => "SQLITE_NOTADB",
+        ffi::SQLITE_NOTICE => "SQLITE_NOTICE",
+        ffi::SQLITE_WARNING => "SQLITE_WARNING",
+        _ => "SQLITE_UNKNOWN",
+    }
+}
+
+// SQLITE_CONSTRAINT: UNIQUE constraint failed: users.email (extended code
+// 2067)
+impl fmt::Display for SQLite3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", error_code_name(self.code), self.message)?;
+        if self.extended_code != self.code {
+            write!(f, " (extended code {})", self.extended_code)?;
+        }
+        Ok(())
+    }
 }
 
 struct RawConnection {
@@ -31,6 +120,11 @@ struct Statement {
     stmt: *mut ffi::sqlite3_stmt,
 }
 
+// SQLite is compiled in serialized mode, connections and statements can be
+// moved to the thread executing the queries.
+unsafe impl Send for RawConnection {}
+unsafe impl Send for Statement {}
+
 impl Drop for Statement {
     fn drop(&mut self) {
         unsafe {
@@ -51,23 +145,50 @@ impl Drop for RawConnection {
 fn create_statement(conn: &RawConnection,
                     query: String)
                     -> Result<Statement, SQLite3Error> {
-    let raw_query = CString::new(query).unwrap();
+    match create_statement_with_tail(conn, &query)? {
+        (Some(stmt), _) => Ok(stmt),
+        (None, _) => {
+            println!("The statement is null!");
+            Err(SQLite3Error::new(ffi::SQLITE_MISUSE,
+                                  String::from("the query does not contain \
+                                                any statement")))
+        }
+    }
+}
 
-    let mut stmt: *mut ffi::sqlite3_stmt = unsafe { mem::uninitialized() };
+// Compile the first statement of the query and return it together with the
+// length of the text it consumed, what follows is the rest of the script.
+// The statement is None when the text consumed holds only whitespaces,
+// comments or an empty statement.
+fn create_statement_with_tail(conn: &RawConnection,
+                              query: &str)
+                              -> Result<(Option<Statement>, usize),
+                                        SQLite3Error> {
+    let mut stmt: *mut ffi::sqlite3_stmt = ptr::null_mut();
+    let mut tail: *const std::os::raw::c_char = ptr::null();
+    let start = query.as_ptr() as *const std::os::raw::c_char;
     unsafe {
         let r = ffi::sqlite3_prepare_v2(conn.db,
-                                        raw_query.as_ptr(),
-                                        -1,
+                                        start,
+                                        query.len() as i32,
                                         &mut stmt,
-                                        ptr::null_mut());
-        if stmt.is_null() {
-            println!("The statement is null!");
-        }
+                                        &mut tail);
         match r {
-            ffi::SQLITE_OK => Ok(Statement { stmt: stmt }),
+            ffi::SQLITE_OK => {
+                let consumed = if tail.is_null() {
+                    query.len()
+                } else {
+                    tail as usize - start as usize
+                };
+                if stmt.is_null() {
+                    Ok((None, consumed))
+                } else {
+                    Ok((Some(Statement { stmt: stmt }), consumed))
+                }
+            }
             x => {
                 println!("Create error: {}", x);
-                Err(SQLite3Error::StatementError)
+                Err(SQLite3Error::from_connection(conn.db))
             }
         }
     }
@@ -88,58 +209,548 @@ fn open_connection(path: String) -> Result<RawConnection, SQLite3Error> {
         ffi::SQLITE_OK => Ok(RawConnection { db: db }),
         x => {
             println!("Open error: {}", x);
-            return Err(SQLite3Error::OpenError);
+            let error = if db.is_null() {
+                SQLite3Error::new(x, String::from("impossible to open the \
+                                                   database"))
+            } else {
+                let error = SQLite3Error::from_connection(db);
+                unsafe {
+                    ffi::sqlite3_close(db);
+                }
+                error
+            };
+            return Err(error);
+        }
+    }
+}
+
+fn backup_connection(src: &RawConnection,
+                     dest: &RawConnection)
+                     -> Result<(), SQLite3Error> {
+    let main = CString::new("main").unwrap();
+    unsafe {
+        let backup = ffi::sqlite3_backup_init(dest.db,
+                                              main.as_ptr(),
+                                              src.db,
+                                              main.as_ptr());
+        if backup.is_null() {
+            return Err(SQLite3Error::from_connection(dest.db));
+        }
+        let step = ffi::sqlite3_backup_step(backup, -1);
+        let finish = ffi::sqlite3_backup_finish(backup);
+        match (step, finish) {
+            (ffi::SQLITE_DONE, ffi::SQLITE_OK) => Ok(()),
+            _ => Err(SQLite3Error::from_connection(dest.db)),
+        }
+    }
+}
+
+static TEMP_DB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn temp_db_path() -> String {
+    let n = TEMP_DB_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let mut path = std::env::temp_dir();
+    path.push(format!("rediSQL_{}_{}.sqlite", process::id(), n));
+    path.to_string_lossy().into_owned()
+}
+
+// Texts and blobs are read together with their length, so they can contain
+// NUL bytes.
+fn column_text_bytes(stmt: &Statement, i: i32) -> Vec<u8> {
+    unsafe {
+        let text = ffi::sqlite3_column_text(stmt.stmt, i);
+        let len = ffi::sqlite3_column_bytes(stmt.stmt, i);
+        if text.is_null() {
+            return Vec::new();
+        }
+        std::slice::from_raw_parts(text, len as usize).to_vec()
+    }
+}
+
+fn column_blob(stmt: &Statement, i: i32) -> Vec<u8> {
+    unsafe {
+        let blob = ffi::sqlite3_column_blob(stmt.stmt, i) as *const u8;
+        let len = ffi::sqlite3_column_bytes(stmt.stmt, i);
+        if blob.is_null() {
+            return Vec::new();
+        }
+        std::slice::from_raw_parts(blob, len as usize).to_vec()
+    }
+}
+
+fn column_text(stmt: &Statement, i: i32) -> String {
+    String::from_utf8_lossy(&column_text_bytes(stmt, i)).into_owned()
+}
+
+// The header rows that precede the rows of a result, see the WITH_HEADERS
+// and WITH_TYPES options.
+#[derive(Clone, Copy, PartialEq)]
+enum Headers {
+    Without,
+    Names,
+    NamesAndTypes,
+}
+
+#[derive(Clone, Copy)]
+struct QueryOptions {
+    headers: Headers,
+    // Reply to INSERT, UPDATE and DELETE with the rowid of the last inserted
+    // row too.
+    last_insert_rowid: bool,
+    // Milliseconds, overrides QUERY_TIMEOUT.
+    timeout: Option<u64>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> QueryOptions {
+        QueryOptions {
+            headers: Headers::Without,
+            last_insert_rowid: false,
+            timeout: None,
+        }
+    }
+}
+
+// A column of a result. Columns that are not a plain table column have no
+// declared type.
+struct Column {
+    name: Vec<u8>,
+    decltype: Option<Vec<u8>>,
+}
+
+impl Column {
+    // SQLite has no boolean type, a column declared BOOLEAN holds 0 and 1.
+    fn is_boolean(&self) -> bool {
+        match self.decltype {
+            Some(ref decltype) => {
+                decltype.eq_ignore_ascii_case(b"BOOLEAN") ||
+                decltype.eq_ignore_ascii_case(b"BOOL")
+            }
+            None => false,
+        }
+    }
+}
+
+fn result_columns(stmt: &Statement) -> Vec<Column> {
+    let n_columns = unsafe { ffi::sqlite3_column_count(stmt.stmt) };
+    let mut columns = Vec::new();
+    for i in 0..n_columns {
+        let name = unsafe { ffi::sqlite3_column_name(stmt.stmt, i) };
+        let decltype = unsafe { ffi::sqlite3_column_decltype(stmt.stmt, i) };
+        columns.push(Column {
+            name: unsafe { CStr::from_ptr(name) }.to_bytes().to_vec(),
+            decltype: if decltype.is_null() {
+                None
+            } else {
+                Some(unsafe { CStr::from_ptr(decltype) }.to_bytes().to_vec())
+            },
+        });
+    }
+    make_names_unique(&mut columns);
+    columns
+}
+
+// The names are the keys of the rows sent as RESP3 maps. SELECT a.id, b.id
+// names both columns id, the second one becomes id:1, or id:2 if a column is
+// already named id:1.
+fn make_names_unique(columns: &mut [Column]) {
+    let mut taken: HashSet<Vec<u8>> =
+        columns.iter().map(|column| column.name.clone()).collect();
+    let mut seen = HashSet::new();
+    for column in columns.iter_mut() {
+        if seen.insert(column.name.clone()) {
+            continue;
+        }
+        let mut suffix = 1;
+        let name = loop {
+            let mut name = column.name.clone();
+            name.extend(format!(":{}", suffix).into_bytes());
+            if taken.insert(name.clone()) {
+                break name;
+            }
+            suffix += 1;
+        };
+        seen.insert(name.clone());
+        column.name = name;
+    }
+}
+
+// The names of the columns and, if asked, their declared types, a null for
+// the columns without one.
+fn header_rows(columns: &[Column], headers: Headers) -> Vec<Row> {
+    let names = columns.iter()
+        .map(|column| Entity::Text { text: column.name.clone() })
+        .collect();
+    let types = columns.iter()
+        .map(|column| match column.decltype {
+            Some(ref decltype) => Entity::Text { text: decltype.clone() },
+            None => Entity::Null,
+        })
+        .collect();
+    match headers {
+        Headers::Without => vec![],
+        Headers::Names => vec![names],
+        Headers::NamesAndTypes => vec![names, types],
+    }
+}
+
+fn hex_literal(bytes: &[u8]) -> String {
+    let mut literal = String::with_capacity(2 * bytes.len() + 3);
+    literal.push_str("X'");
+    for byte in bytes {
+        literal.push_str(&format!("{:02X}", byte));
+    }
+    literal.push('\'');
+    literal
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace("\"", "\"\""))
+}
+
+// Render the i-th column of the current row as a SQL literal that, once
+// inserted back, gives exactly the same value and storage class.
+fn column_as_sql_literal(stmt: &Statement, i: i32) -> String {
+    unsafe {
+        match ffi::sqlite3_column_type(stmt.stmt, i) {
+            ffi::SQLITE_INTEGER => {
+                format!("{}", ffi::sqlite3_column_int64(stmt.stmt, i))
+            }
+            ffi::SQLITE_FLOAT => {
+                let value = ffi::sqlite3_column_double(stmt.stmt, i);
+                if value.is_infinite() && value > 0.0 {
+                    String::from("9e999")
+                } else if value.is_infinite() {
+                    String::from("-9e999")
+                } else {
+                    format!("{:?}", value)
+                }
+            }
+            ffi::SQLITE_TEXT => {
+                let bytes = column_text_bytes(stmt, i);
+                match String::from_utf8(bytes) {
+                    Ok(ref text) if !text.contains('\0') => {
+                        format!("'{}'", text.replace("'", "''"))
+                    }
+                    Ok(text) => {
+                        format!("CAST({} AS TEXT)", hex_literal(text.as_bytes()))
+                    }
+                    Err(e) => {
+                        format!("CAST({} AS TEXT)", hex_literal(e.as_bytes()))
+                    }
+                }
+            }
+            ffi::SQLITE_BLOB => hex_literal(&column_blob(stmt, i)),
+            _ => String::from("NULL"),
+        }
+    }
+}
+
+const AOF_INSERT_BATCH_ROWS: usize = 100;
+const AOF_INSERT_BATCH_BYTES: usize = 64 * 1024;
+
+fn dump_table<F>(conn: &RawConnection,
+                 table: &str,
+                 emit: &mut F)
+                 -> Result<(), SQLite3Error>
+    where F: FnMut(&str)
+{
+    // Rowid tables are dumped with their rowid, otherwise the rows of a
+    // table without an INTEGER PRIMARY KEY would be renumbered once inserted
+    // back. WITHOUT ROWID tables fail to prepare the first select.
+    let with_rowid = format!("SELECT rowid, * FROM {};",
+                             quote_identifier(table));
+    let (stmt, first_column) = match create_statement(conn, with_rowid) {
+        Ok(stmt) => (stmt, 1),
+        Err(_) => {
+            let select = format!("SELECT * FROM {};", quote_identifier(table));
+            (create_statement(conn, select)?, 0)
+        }
+    };
+    let n_columns = unsafe { ffi::sqlite3_column_count(stmt.stmt) };
+    let mut columns: Vec<String> = Vec::new();
+    for i in first_column..n_columns {
+        let name = unsafe {
+            CStr::from_ptr(ffi::sqlite3_column_name(stmt.stmt, i))
+                .to_string_lossy()
+                .into_owned()
+        };
+        columns.push(quote_identifier(&name));
+    }
+    // A column called like the rowid hides it, the rows are then dumped
+    // without the rowid.
+    let shadowed = columns.iter().any(|name| {
+        let name = name.to_lowercase();
+        name == "\"rowid\"" || name == "\"oid\"" || name == "\"_rowid_\""
+    });
+    let first_column = if first_column == 1 && !shadowed {
+        columns.insert(0, String::from("rowid"));
+        0
+    } else {
+        first_column
+    };
+    let insert = format!("INSERT INTO {}({}) VALUES ",
+                         quote_identifier(table),
+                         columns.join(","));
+
+    let mut batch = String::new();
+    let mut rows_in_batch = 0;
+    loop {
+        match unsafe { ffi::sqlite3_step(stmt.stmt) } {
+            ffi::SQLITE_ROW => {
+                batch.push_str(if rows_in_batch == 0 { &insert } else { "," });
+                batch.push('(');
+                for i in first_column..n_columns {
+                    if i > first_column {
+                        batch.push(',');
+                    }
+                    batch.push_str(&column_as_sql_literal(&stmt, i));
+                }
+                batch.push(')');
+                rows_in_batch += 1;
+                if rows_in_batch >= AOF_INSERT_BATCH_ROWS ||
+                   batch.len() >= AOF_INSERT_BATCH_BYTES {
+                    batch.push(';');
+                    emit(&batch);
+                    batch.clear();
+                    rows_in_batch = 0;
+                }
+            }
+            ffi::SQLITE_DONE => break,
+            _ => return Err(SQLite3Error::from_statement(&stmt)),
         }
     }
+    if rows_in_batch > 0 {
+        batch.push(';');
+        emit(&batch);
+    }
+    Ok(())
+}
+
+// Produce the sequence of SQL statements that rebuild the whole database:
+// first the tables, then their rows and last the indexes, the views and the
+// triggers, so that the triggers do not fire while the rows are inserted
+// back.
+fn dump_database<F>(conn: &RawConnection,
+                    mut emit: F)
+                    -> Result<(), SQLite3Error>
+    where F: FnMut(&str)
+{
+    let schema = create_statement(conn,
+                                  String::from("SELECT type, name, sql FROM \
+                                                sqlite_master WHERE sql IS \
+                                                NOT NULL AND substr(name, 1, \
+                                                7) != 'sqlite_' ORDER BY \
+                                                rowid;"))?;
+    let mut tables: Vec<(String, String)> = Vec::new();
+    let mut others: Vec<String> = Vec::new();
+    let mut has_sequence = false;
+    loop {
+        match unsafe { ffi::sqlite3_step(schema.stmt) } {
+            ffi::SQLITE_ROW => {
+                let kind = column_text(&schema, 0);
+                let name = column_text(&schema, 1);
+                let sql = column_text(&schema, 2);
+                if kind == "table" {
+                    if sql.to_uppercase().contains("AUTOINCREMENT") {
+                        has_sequence = true;
+                    }
+                    tables.push((name, sql));
+                } else {
+                    others.push(sql);
+                }
+            }
+            ffi::SQLITE_DONE => break,
+            _ => return Err(SQLite3Error::from_statement(&schema)),
+        }
+    }
+
+    for &(_, ref sql) in &tables {
+        emit(&format!("{};", sql));
+    }
+    for &(ref name, _) in &tables {
+        dump_table(conn, name, &mut emit)?;
+    }
+    if has_sequence {
+        emit("DELETE FROM sqlite_sequence;");
+        dump_table(conn, "sqlite_sequence", &mut emit)?;
+    }
+    for sql in &others {
+        emit(&format!("{};", sql));
+    }
+    Ok(())
 }
 
-enum Cursor {
+enum Cursor<'a> {
     OKCursor,
     DONECursor,
     RowsCursor {
-        stmt: Statement,
+        stmt: &'a Statement,
         num_columns: i32,
-        types: Vec<EntityType>,
         previous_status: i32,
     },
 }
 
-fn execute_statement(stmt: Statement) -> Result<Cursor, SQLite3Error> {
+enum Parameter {
+    Integer { int: i64 },
+    Float { float: f64 },
+    Text { text: Vec<u8> },
+    Blob { blob: Vec<u8> },
+    Null,
+}
+
+// Arguments are bound as INTEGER or FLOAT when they parse as such and as TEXT
+// otherwise. The type can be forced by prefixing the value with an explicit
+// marker: INTEGER:, FLOAT:, TEXT:, BLOB: or NULL:, so that "TEXT:42" is bound
+// as the string "42" and "NULL:" as NULL. Texts and blobs are bound byte by
+// byte, as they were sent. A value that does not match its marker is an
+// error, it is never bound as something else.
+fn parse_parameter(arg: &[u8]) -> Result<Parameter, String> {
+    let marker = arg.iter()
+        .position(|&b| b == b':')
+        .map(|i| (&arg[..i], &arg[i + 1..]));
+    let as_number = |value: &[u8]| String::from_utf8(value.to_vec()).ok();
+    let invalid = |marker: &str, value: &[u8]| {
+        format!("Invalid {} parameter: {}",
+                marker,
+                String::from_utf8_lossy(value))
+    };
+    match marker {
+        Some((b"INTEGER", value)) => {
+            match as_number(value).and_then(|v| v.parse::<i64>().ok()) {
+                Some(int) => Ok(Parameter::Integer { int: int }),
+                None => Err(invalid("INTEGER", value)),
+            }
+        }
+        Some((b"FLOAT", value)) => {
+            match as_number(value).and_then(|v| v.parse::<f64>().ok()) {
+                Some(float) => Ok(Parameter::Float { float: float }),
+                None => Err(invalid("FLOAT", value)),
+            }
+        }
+        Some((b"TEXT", value)) => Ok(Parameter::Text { text: value.to_vec() }),
+        Some((b"BLOB", value)) => Ok(Parameter::Blob { blob: value.to_vec() }),
+        Some((b"NULL", b"")) => Ok(Parameter::Null),
+        Some((b"NULL", value)) => Err(invalid("NULL", value)),
+        // Only a number written the way it is written back is inferred,
+        // 007 or +5 stay texts, as the client sent them.
+        _ => {
+            let number = as_number(arg);
+            if let Some(int) = number.as_ref()
+                .and_then(|v| v.parse::<i64>().ok())
+                .filter(|int| number == Some(int.to_string())) {
+                Ok(Parameter::Integer { int: int })
+            } else if let Some(float) = number.as_ref()
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|float| float.is_finite())
+                .filter(|float| {
+                    number == Some(float.to_string()) ||
+                    number == Some(format!("{:?}", float))
+                }) {
+                Ok(Parameter::Float { float: float })
+            } else {
+                Ok(Parameter::Text { text: arg.to_vec() })
+            }
+        }
+    }
+}
+
+fn bind_parameters(stmt: &Statement,
+                   parameters: &[Parameter])
+                   -> Result<(), SQLite3Error> {
+    reset_statement(stmt);
+    let expected = unsafe { ffi::sqlite3_bind_parameter_count(stmt.stmt) };
+    if expected as usize != parameters.len() {
+        let message = format!("the statement expects {} parameters, {} \
+                               were provided",
+                              expected,
+                              parameters.len());
+        return Err(SQLite3Error::new(ffi::SQLITE_RANGE, message));
+    }
+    // SQLITE_TRANSIENT, SQLite makes its own copy of texts and blobs.
+    let transient: ffi::sqlite3_destructor_type =
+        unsafe { mem::transmute(-1isize) };
+    for (i, parameter) in parameters.iter().enumerate() {
+        let index = i as i32 + 1;
+        let r = unsafe {
+            match *parameter {
+                Parameter::Integer { int } => {
+                    ffi::sqlite3_bind_int64(stmt.stmt, index, int)
+                }
+                Parameter::Float { float } => {
+                    ffi::sqlite3_bind_double(stmt.stmt, index, float)
+                }
+                Parameter::Text { ref text } => {
+                    ffi::sqlite3_bind_text(stmt.stmt,
+                                           index,
+                                           text.as_ptr() as *const i8,
+                                           text.len() as i32,
+                                           transient)
+                }
+                Parameter::Blob { ref blob } => {
+                    ffi::sqlite3_bind_blob(stmt.stmt,
+                                           index,
+                                           blob.as_ptr() as
+                                           *const std::os::raw::c_void,
+                                           blob.len() as i32,
+                                           transient)
+                }
+                Parameter::Null => ffi::sqlite3_bind_null(stmt.stmt, index),
+            }
+        };
+        if r != ffi::SQLITE_OK {
+            return Err(SQLite3Error::from_statement(stmt));
+        }
+    }
+    Ok(())
+}
+
+fn reset_statement(stmt: &Statement) {
+    unsafe {
+        ffi::sqlite3_reset(stmt.stmt);
+        ffi::sqlite3_clear_bindings(stmt.stmt);
+    }
+}
+
+fn execute_statement<'a>(stmt: &'a Statement)
+                         -> Result<Cursor<'a>, SQLite3Error> {
 
     match unsafe { ffi::sqlite3_step(stmt.stmt) } {
         ffi::SQLITE_OK => Ok(Cursor::OKCursor),
         ffi::SQLITE_DONE => Ok(Cursor::DONECursor),
-        ffi::SQLITE_ROW => {
-            let n_columns =
-                unsafe { ffi::sqlite3_column_count(stmt.stmt) } as i32;
-            let mut types: Vec<EntityType> = Vec::new();
-            for i in 0..n_columns {
-                types.push(match unsafe {
-                    ffi::sqlite3_column_type(stmt.stmt, i)
-                } {
-                    ffi::SQLITE_INTEGER => EntityType::Integer,
-                    ffi::SQLITE_FLOAT => EntityType::Float,
-                    ffi::SQLITE_TEXT => EntityType::Text,
-                    ffi::SQLITE_BLOB => EntityType::Blob,
-                    ffi::SQLITE_NULL => EntityType::Null,
-                    _ => EntityType::Null,
-                })
-            }
-            Ok(Cursor::RowsCursor {
-                stmt: stmt,
-                num_columns: n_columns,
-                types: types,
-                previous_status: ffi::SQLITE_ROW,
-            })
-        }
+        ffi::SQLITE_ROW => Ok(rows_cursor(stmt)),
         x => {
             println!("Exec error: {}", x);
-            return Err(SQLite3Error::ExecuteError);
+            return Err(SQLite3Error::from_statement(stmt));
         }
     }
 
 }
 
+// The cursor over the rows of a statement that has just been stepped on a
+// row.
+fn rows_cursor<'a>(stmt: &'a Statement) -> Cursor<'a> {
+    let n_columns = unsafe { ffi::sqlite3_column_count(stmt.stmt) } as i32;
+    Cursor::RowsCursor {
+        stmt: stmt,
+        num_columns: n_columns,
+        previous_status: ffi::SQLITE_ROW,
+    }
+}
+
+// SQLite is dynamically typed, the type is read for every single cell of the
+// current row.
+fn column_type(stmt: &Statement, i: i32) -> EntityType {
+    match unsafe { ffi::sqlite3_column_type(stmt.stmt, i) } {
+        ffi::SQLITE_INTEGER => EntityType::Integer,
+        ffi::SQLITE_FLOAT => EntityType::Float,
+        ffi::SQLITE_TEXT => EntityType::Text,
+        ffi::SQLITE_BLOB => EntityType::Blob,
+        ffi::SQLITE_NULL => EntityType::Null,
+        _ => EntityType::Null,
+    }
+}
+
 enum EntityType {
     Integer,
     Float,
@@ -149,16 +760,96 @@ enum EntityType {
 }
 
 enum Entity {
-    Integer { int: i32 },
+    Integer { int: i64 },
     Float { float: f64 },
-    Text { text: String },
-    Blob { blob: String },
+    Text { text: Vec<u8> },
+    Blob { blob: Vec<u8> },
     Null,
     OK,
     DONE,
 }
 
 
+// When the module is loaded with the BIGINT_AS_STRING option integers that a
+// double cannot represent exactly are replied as strings, for the clients
+// that parse every RESP integer into a double.
+static BIGINT_AS_STRING: AtomicBool = AtomicBool::new(false);
+// Replicate the rows a write changed instead of the write itself, set with
+// the REPLICATE_EFFECTS module option.
+static REPLICATE_EFFECTS: AtomicBool = AtomicBool::new(false);
+// Milliseconds a command can run before being interrupted, 0 for no limit.
+// Set with the QUERY_TIMEOUT module option, the TIMEOUT option of the query
+// commands overrides it.
+static QUERY_TIMEOUT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    // The only directory whose files the clients can name, set with the
+    // FILE_DIRECTORY module option. Without it they cannot name any.
+    static ref FILE_DIRECTORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+// The path of a file named by a client, resolved in FILE_DIRECTORY. An
+// absolute path must be inside it too, and neither .. nor a symbolic link
+// may lead outside of it. The file may not exist yet, its directory must.
+fn confined_path(path: &str) -> Result<String, String> {
+    let directory = match *FILE_DIRECTORY.lock().unwrap() {
+        Some(ref directory) => directory.clone(),
+        None => {
+            return Err(String::from("ERR - Error, files are disabled, see \
+                                     the FILE_DIRECTORY module option"))
+        }
+    };
+    let outside = || {
+        format!("ERR - Error, {} is not in the FILE_DIRECTORY {}",
+                path,
+                directory.display())
+    };
+    let joined = directory.join(path);
+    let resolved = match fs::canonicalize(&joined) {
+        Ok(resolved) => resolved,
+        // A symbolic link to a file that does not exist.
+        Err(_) if fs::symlink_metadata(&joined).is_ok() => {
+            return Err(outside())
+        }
+        Err(_) => {
+            match (joined.parent(), joined.file_name()) {
+                (Some(parent), Some(name)) => {
+                    fs::canonicalize(parent)
+                        .map(|parent| parent.join(name))
+                        .map_err(|e| {
+                            format!("ERR - Error, cannot open {}: {}", path, e)
+                        })?
+                }
+                _ => return Err(outside()),
+            }
+        }
+    };
+    if !resolved.starts_with(&directory) {
+        return Err(outside());
+    }
+    resolved.to_str().map(String::from).ok_or_else(outside)
+}
+
+// A database file is saved and replicated relative to the FILE_DIRECTORY:
+// every server, replicas on the same host included, opens it in its own.
+fn relative_path(file: &str) -> String {
+    match *FILE_DIRECTORY.lock().unwrap() {
+        Some(ref directory) => {
+            Path::new(file)
+                .strip_prefix(directory)
+                .ok()
+                .and_then(|relative| relative.to_str())
+                .map_or(String::from(file), String::from)
+        }
+        None => String::from(file),
+    }
+}
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+// NULL and REAL go through ReplyWithNull and ReplyWithDouble, that a server
+// speaking RESP3 with the client renders as native null and double. The
+// shape of the results is chosen by reply_with_row and the RESP3 envelope
+// below.
 trait RedisReply {
     fn reply(&self, ctx: *mut ffi::RedisModuleCtx);
 }
@@ -168,19 +859,22 @@ impl RedisReply for Entity {
         unsafe {
             match *self {
                 Entity::Integer { int } => {
-                    ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx,
-                                                                int as i64);
+                    if BIGINT_AS_STRING.load(Ordering::Relaxed) &&
+                       (int > MAX_SAFE_INTEGER || int < -MAX_SAFE_INTEGER) {
+                        let text = int.to_string();
+                        ffi::RedisModule_ReplyWithStringBuffer.unwrap()(ctx, text.as_ptr() as *const i8, text.len());
+                    } else {
+                        ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx, int);
+                    }
                 }
                 Entity::Float { float } => {
                     ffi::RedisModule_ReplyWithDouble.unwrap()(ctx, float);
                 }
                 Entity::Text { ref text } => {
-                    let text_c = CString::new(text.clone()).unwrap();
-                    ffi::RedisModule_ReplyWithStringBuffer.unwrap()(ctx, text_c.as_ptr(), text.len());
+                    ffi::RedisModule_ReplyWithStringBuffer.unwrap()(ctx, text.as_ptr() as *const i8, text.len());
                 }
                 Entity::Blob { ref blob } => {
-                    let blob_c = CString::new(blob.clone()).unwrap();
-                    ffi::RedisModule_ReplyWithStringBuffer.unwrap()(ctx, blob_c.as_ptr(), blob.len());
+                    ffi::RedisModule_ReplyWithStringBuffer.unwrap()(ctx, blob.as_ptr() as *const i8, blob.len());
                 }
                 Entity::Null => {
                     ffi::RedisModule_ReplyWithNull.unwrap()(ctx);
@@ -204,7 +898,144 @@ impl RedisReply for Entity {
 
 type Row = Vec<Entity>;
 
-impl Iterator for Cursor {
+// Whether the client negotiated RESP3 and the server has the replies to
+// answer it in kind. Older servers and RESP2 clients get arrays.
+fn speaks_resp3(ctx: *mut ffi::RedisModuleCtx) -> bool {
+    if unsafe { ffi::RedisModule_ReplyWithMap }.is_none() ||
+       unsafe { ffi::RedisModule_ReplyWithBool }.is_none() {
+        return false;
+    }
+    match unsafe { ffi::RedisModule_GetContextFlags } {
+        Some(get_context_flags) => {
+            let flags = unsafe { get_context_flags(ctx) };
+            flags & ffi::REDISMODULE_CTX_FLAGS_RESP3 != 0
+        }
+        None => false,
+    }
+}
+
+fn reply_with_field(ctx: *mut ffi::RedisModuleCtx, field: &[u8]) {
+    unsafe {
+        ffi::RedisModule_ReplyWithStringBuffer.unwrap()(ctx,
+                                                        field.as_ptr() as
+                                                        *const i8,
+                                                        field.len());
+    }
+}
+
+// A row is an array in RESP2 and a map from the name of the column to its
+// value in RESP3, where the integers of a BOOLEAN column are booleans.
+fn reply_with_row(ctx: *mut ffi::RedisModuleCtx,
+                  columns: &[Column],
+                  row: &[Entity],
+                  resp3: bool) {
+    if !resp3 {
+        unsafe {
+            ffi::RedisModule_ReplyWithArray.unwrap()(ctx, row.len() as i64);
+        }
+        for entity in row {
+            entity.reply(ctx);
+        }
+        return;
+    }
+    unsafe {
+        ffi::RedisModule_ReplyWithMap.unwrap()(ctx, row.len() as i64);
+    }
+    for (i, entity) in row.iter().enumerate() {
+        let column = columns.get(i);
+        match column {
+            Some(column) => reply_with_field(ctx, &column.name),
+            None => reply_with_field(ctx, i.to_string().as_bytes()),
+        }
+        let boolean = column.map_or(false, Column::is_boolean);
+        match *entity {
+            Entity::Integer { int } if boolean && (int == 0 || int == 1) => {
+                unsafe {
+                    ffi::RedisModule_ReplyWithBool.unwrap()(ctx, int as i32);
+                }
+            }
+            _ => entity.reply(ctx),
+        }
+    }
+}
+
+// In RESP3 a result is a map: the names of the columns, their declared
+// types with WITH_TYPES, the rows and the number of rows changed. The names
+// of the columns are always there, WITH_HEADERS is implied. The caller
+// replies with the rows, then ends the map with end_resp3_result.
+fn begin_resp3_result(ctx: *mut ffi::RedisModuleCtx,
+                      columns: &[Column],
+                      headers: Headers,
+                      last_insert_rowid: bool) {
+    let mut len = 3;
+    if headers == Headers::NamesAndTypes {
+        len += 1;
+    }
+    if last_insert_rowid {
+        len += 1;
+    }
+    unsafe {
+        ffi::RedisModule_ReplyWithMap.unwrap()(ctx, len);
+    }
+    let fields: &[&[u8]] = if headers == Headers::NamesAndTypes {
+        &[b"columns", b"types"]
+    } else {
+        &[b"columns"]
+    };
+    let header = header_rows(columns, Headers::NamesAndTypes);
+    for (field, row) in fields.iter().zip(header.iter()) {
+        reply_with_field(ctx, field);
+        unsafe {
+            ffi::RedisModule_ReplyWithArray.unwrap()(ctx, row.len() as i64);
+        }
+        for entity in row {
+            entity.reply(ctx);
+        }
+    }
+    reply_with_field(ctx, b"rows");
+}
+
+fn end_resp3_result(ctx: *mut ffi::RedisModuleCtx,
+                    changes: i64,
+                    last_insert_rowid: Option<i64>) {
+    reply_with_field(ctx, b"changes");
+    unsafe {
+        ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx, changes);
+    }
+    if let Some(rowid) = last_insert_rowid {
+        reply_with_field(ctx, b"last_insert_rowid");
+        unsafe {
+            ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx, rowid);
+        }
+    }
+}
+
+impl<'a> Cursor<'a> {
+    // Whether the cursor stands on a row still to be read.
+    fn has_rows(&self) -> bool {
+        match *self {
+            Cursor::RowsCursor { previous_status, .. } => {
+                previous_status == ffi::SQLITE_ROW
+            }
+            _ => false,
+        }
+    }
+
+    // The error that stopped the iteration, if any.
+    fn error(&self) -> Option<SQLite3Error> {
+        match *self {
+            Cursor::RowsCursor { stmt, previous_status, .. } => {
+                match previous_status {
+                    ffi::SQLITE_ROW | ffi::SQLITE_DONE => None,
+                    _ => Some(SQLite3Error::from_statement(stmt)),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for Cursor<'a> {
     type Item = Row;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -212,20 +1043,19 @@ impl Iterator for Cursor {
             Cursor::OKCursor => Some(vec![Entity::OK]),
             Cursor::DONECursor => Some(vec![Entity::DONE]),
 
-            Cursor::RowsCursor { ref stmt,
+            Cursor::RowsCursor { stmt,
                                  num_columns,
-                                 ref types,
                                  ref mut previous_status } => {
                 match *previous_status {
                     ffi::SQLITE_ROW => {
                         let mut result = vec![];
                         for i in 0..num_columns {
                             let entity_value =
-                                match types[i as usize] {
+                                match column_type(stmt, i) {
                                     EntityType::Integer => {
                                         let value =
                                             unsafe {
-                                                ffi::sqlite3_column_int(stmt.stmt, i)
+                                                ffi::sqlite3_column_int64(stmt.stmt, i)
                                             };
                                         Entity::Integer { int: value }
                                     }
@@ -234,17 +1064,11 @@ impl Iterator for Cursor {
                                         Entity::Float { float: value }
                                     }
                                     EntityType::Text => {
-                                        let value =
-                                unsafe {
-                                    CStr::from_ptr(ffi::sqlite3_column_text(stmt.stmt, i) as *const i8).to_string_lossy().into_owned()
-                                };
+                                        let value = column_text_bytes(stmt, i);
                                         Entity::Text { text: value }
                                     }
                                     EntityType::Blob => {
-                                        let value = 
-                                unsafe { 
-                                    CStr::from_ptr(ffi::sqlite3_column_blob(stmt.stmt, i) as *const i8).to_string_lossy().into_owned() 
-                                };
+                                        let value = column_blob(stmt, i);
                                         Entity::Blob { blob: value }
                                     }
                                     EntityType::Null => Entity::Null {},
@@ -278,262 +1102,3292 @@ fn create_argument(ctx: *mut ffi::RedisModuleCtx,
     (context, argvector)
 }
 
-#[repr(C)]
-struct db_connection {
+// Everything living on the SQLite side of a key, it is shared between Redis
+// and the thread executing the queries.
+struct Database {
     connection: RawConnection,
+    // Name -> (SQL, compiled statement)
+    statements: HashMap<String, (String, Statement)>,
+    // Written by the SQLite hooks, it must outlive the connection.
+    effects: Box<RefCell<Effects>>,
+    cursors: HashMap<usize, ServerCursor>,
+    // Read by the progress handler, it must outlive the connection.
+    timeout: Box<QueryTimeout>,
+    transaction: Option<Transaction>,
+    // The named statement whose rows a worker is sending. Cleared when
+    // another command uses the statement, which ends the stream.
+    streaming: Option<String>,
+    // The writes executed but not yet propagated, in the order they were
+    // executed.
+    outbox: Vec<Replication>,
 }
 
-struct RedisModuleString {
-    rm_string: *mut ffi::RedisModuleString,
+// A transaction opened with REDISQL.BEGIN, it belongs to the client that
+// opened it.
+struct Transaction {
+    client_id: u64,
+    last_used: Instant,
+    // The writes of the transaction, BEGIN included. They are propagated
+    // once it commits and dropped if it rolls back.
+    replication: Vec<Replication>,
+    // A copy of the database taken at BEGIN. While the transaction is open
+    // the RDB and the AOF are written from it, they only ever get what is
+    // committed.
+    committed: RawConnection,
 }
 
-fn create_rm_string(ctx: *mut ffi::RedisModuleCtx,
-                    s: String)
-                    -> RedisModuleString {
-    let l = s.len();
-    let cs = CString::new(s).unwrap();
+// The transactions of the clients that disconnect are rolled back as soon
+// as Redis reports it, see client_changed. Should a disconnection be missed,
+// the transactions left idle for this long are rolled back anyway, when the
+// next command for the database arrives.
+const TRANSACTION_IDLE_TIMEOUT: u64 = 60;
 
+// The deadline of the command being executed, once it has passed, or once
+// the command has been killed, the progress handler interrupts the
+// statement.
+#[derive(Default)]
+struct QueryTimeout {
+    deadline: Cell<Option<Instant>>,
+    milliseconds: Cell<u64>,
+    expired: Cell<bool>,
+    // Set by REDISQL.KILL from the main thread.
+    killed: Arc<AtomicBool>,
+}
 
-    RedisModuleString {
-        rm_string: unsafe {
-            ffi::RedisModule_CreateString.unwrap()(ctx, cs.as_ptr(), l)
-        },
+impl QueryTimeout {
+    fn start(&self, milliseconds: u64) {
+        self.expired.set(false);
+        self.killed.store(false, Ordering::SeqCst);
+        self.milliseconds.set(milliseconds);
+        self.deadline.set(if milliseconds > 0 {
+            Some(Instant::now() + Duration::from_millis(milliseconds))
+        } else {
+            None
+        });
     }
-}
 
-#[repr(C)]
-struct RedisKey {
-    key: *mut ffi::RedisModuleKey,
+    fn stop(&self) {
+        self.deadline.set(None);
+    }
+
+    fn error(&self) -> String {
+        format!("ERR - Timeout, the query did not complete in {} ms",
+                self.milliseconds.get())
+    }
 }
 
-impl Drop for RedisKey {
-    fn drop(&mut self) {
-        println!("Key closed");
+// Virtual machine instructions between two checks of the deadline.
+const PROGRESS_HANDLER_PERIOD: i32 = 1000;
+
+unsafe extern "C" fn check_deadline(timeout: *mut std::os::raw::c_void)
+                                    -> std::os::raw::c_int {
+    let timeout = &*(timeout as *const QueryTimeout);
+    if timeout.killed.load(Ordering::SeqCst) {
+        return 1;
+    }
+    match timeout.deadline.get() {
+        Some(deadline) if Instant::now() >= deadline => {
+            timeout.expired.set(true);
+            1
+        }
+        _ => 0,
+    }
+}
+
+// A statement kept open between commands, see REDISQL.QUERY_CURSOR.
+struct ServerCursor {
+    stmt: Statement,
+    // False once the last row has been fetched.
+    has_rows: bool,
+    // Repeated at the start of every page.
+    headers: Headers,
+    last_used: Instant,
+}
+
+static CURSOR_COUNTER: AtomicUsize = AtomicUsize::new(1);
+// Cursors not used for this long are closed by the next command for the
+// database.
+const CURSOR_IDLE_TIMEOUT: u64 = 300;
+
+fn create_database(connection: RawConnection) -> Database {
+    let database = Database {
+        connection: connection,
+        statements: HashMap::new(),
+        effects: Box::new(RefCell::new(Effects::default())),
+        cursors: HashMap::new(),
+        timeout: Box::new(QueryTimeout::default()),
+        transaction: None,
+        streaming: None,
+        outbox: Vec::new(),
+    };
+    let effects = &*database.effects as *const RefCell<Effects> as
+                  *mut std::os::raw::c_void;
+    unsafe {
+        ffi::sqlite3_update_hook(database.connection.db,
+                                 Some(record_row_change),
+                                 effects);
+        ffi::sqlite3_rollback_hook(database.connection.db,
+                                   Some(discard_effects),
+                                   effects);
+        ffi::sqlite3_set_authorizer(database.connection.db,
+                                    Some(authorize_write),
+                                    effects);
+        ffi::sqlite3_progress_handler(database.connection.db,
+                                      PROGRESS_HANDLER_PERIOD,
+                                      Some(check_deadline),
+                                      &*database.timeout as
+                                      *const QueryTimeout as
+                                      *mut std::os::raw::c_void);
+    }
+    database
+}
+
+impl Database {
+    // What is committed, see Transaction.committed.
+    fn committed_connection(&self) -> &RawConnection {
+        match self.transaction {
+            Some(ref transaction) => &transaction.committed,
+            None => &self.connection,
+        }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // The statements must be finalized before the connection is closed.
+        self.statements.clear();
+        self.cursors.clear();
+    }
+}
+
+// A row touched by a write, as reported by the update hook. Tables WITHOUT
+// ROWID and virtual tables are never reported.
+#[derive(PartialEq, Eq, Hash)]
+struct RowChange {
+    database: String,
+    table: String,
+    rowid: i64,
+}
+
+#[derive(Default)]
+struct Effects {
+    // Rows touched by the statement being executed.
+    rows: Vec<RowChange>,
+    // What the statements of the open transaction did, as SQL statements.
+    pending: Vec<String>,
+    // (database, table) written by the statements compiled since the last
+    // time it was cleared.
+    targets: HashSet<(String, String)>,
+    // The table a DROP being compiled removes, see authorize_write.
+    dropped: Option<String>,
+}
+
+unsafe extern "C" fn record_row_change(effects: *mut std::os::raw::c_void,
+                                       _operation: std::os::raw::c_int,
+                                       database: *const std::os::raw::c_char,
+                                       table: *const std::os::raw::c_char,
+                                       rowid: ffi::sqlite3_int64) {
+    if !REPLICATE_EFFECTS.load(Ordering::Relaxed) {
+        return;
+    }
+    let effects = &*(effects as *const RefCell<Effects>);
+    effects.borrow_mut().rows.push(RowChange {
+        database: CStr::from_ptr(database).to_string_lossy().into_owned(),
+        table: CStr::from_ptr(table).to_string_lossy().into_owned(),
+        rowid: rowid,
+    });
+}
+
+// The authorizer of the connection, it lets everything through. It records
+// the tables a statement writes, triggers included, and disables the
+// truncate optimization of DELETE without WHERE, which deletes the rows
+// without reporting them to the update hook.
+unsafe extern "C" fn authorize_write(effects: *mut std::os::raw::c_void,
+                                     action: std::os::raw::c_int,
+                                     table: *const std::os::raw::c_char,
+                                     _column: *const std::os::raw::c_char,
+                                     database: *const std::os::raw::c_char,
+                                     _trigger: *const std::os::raw::c_char)
+                                     -> std::os::raw::c_int {
+    if !REPLICATE_EFFECTS.load(Ordering::Relaxed) {
+        return ffi::SQLITE_OK;
+    }
+    let effects = &*(effects as *const RefCell<Effects>);
+    let mut effects = effects.borrow_mut();
+    let dropped = effects.dropped.take();
+    match action {
+        ffi::SQLITE_DROP_TABLE |
+        ffi::SQLITE_DROP_TEMP_TABLE |
+        ffi::SQLITE_DROP_VIEW |
+        ffi::SQLITE_DROP_TEMP_VIEW |
+        ffi::SQLITE_DROP_VTABLE => {
+            effects.dropped =
+                Some(CStr::from_ptr(table).to_string_lossy().into_owned());
+            return ffi::SQLITE_OK;
+        }
+        ffi::SQLITE_INSERT | ffi::SQLITE_UPDATE | ffi::SQLITE_DELETE => {}
+        _ => return ffi::SQLITE_OK,
+    }
+    let table = CStr::from_ptr(table).to_string_lossy().into_owned();
+    // A DROP deletes from the schema tables, and then the dropped table
+    // itself: both DELETE would be skipped.
+    if table.starts_with("sqlite_") || dropped.as_ref() == Some(&table) {
+        return ffi::SQLITE_OK;
+    }
+    let database = if database.is_null() {
+        String::from("main")
+    } else {
+        CStr::from_ptr(database).to_string_lossy().into_owned()
+    };
+    effects.targets.insert((database, table));
+    if action == ffi::SQLITE_DELETE {
+        ffi::SQLITE_IGNORE
+    } else {
+        ffi::SQLITE_OK
+    }
+}
+
+unsafe extern "C" fn discard_effects(effects: *mut std::os::raw::c_void) {
+    let effects = &*(effects as *const RefCell<Effects>);
+    let mut effects = effects.borrow_mut();
+    effects.rows.clear();
+    effects.pending.clear();
+}
+
+fn schema_version(conn: &RawConnection) -> Result<i64, SQLite3Error> {
+    let stmt = create_statement(conn,
+                                String::from("PRAGMA schema_version;"))?;
+    match unsafe { ffi::sqlite3_step(stmt.stmt) } {
+        ffi::SQLITE_ROW => {
+            Ok(unsafe { ffi::sqlite3_column_int64(stmt.stmt, 0) })
+        }
+        _ => Err(SQLite3Error::from_statement(&stmt)),
+    }
+}
+
+// The statement that sets the row to its current content, or deletes it if
+// it does not exist anymore.
+fn row_effect(conn: &RawConnection,
+              change: &RowChange)
+              -> Result<String, SQLite3Error> {
+    let table = format!("{}.{}",
+                        quote_identifier(&change.database),
+                        quote_identifier(&change.table));
+    let select = format!("SELECT rowid, * FROM {} WHERE rowid = {};",
+                         table,
+                         change.rowid);
+    let stmt = create_statement(conn, select)?;
+    match unsafe { ffi::sqlite3_step(stmt.stmt) } {
+        ffi::SQLITE_ROW => {
+            let n_columns = unsafe { ffi::sqlite3_column_count(stmt.stmt) };
+            let mut columns = vec![String::from("rowid")];
+            let mut values = vec![change.rowid.to_string()];
+            for i in 1..n_columns {
+                let name = unsafe {
+                    CStr::from_ptr(ffi::sqlite3_column_name(stmt.stmt, i))
+                };
+                columns.push(quote_identifier(&name.to_string_lossy()));
+                values.push(column_as_sql_literal(&stmt, i));
+            }
+            Ok(format!("INSERT OR REPLACE INTO {}({}) VALUES({});",
+                       table,
+                       columns.join(","),
+                       values.join(",")))
+        }
+        ffi::SQLITE_DONE => {
+            Ok(format!("DELETE FROM {} WHERE rowid = {};",
+                       table,
+                       change.rowid))
+        }
+        _ => Err(SQLite3Error::from_statement(&stmt)),
+    }
+}
+
+// Run a statement and, when the effects are replicated, record what it did:
+// the final content of every row it touched or, when the update hook cannot
+// report its rows, the statement itself with its parameters. That is the
+// case of the statements changing the schema, CREATE TABLE ... AS SELECT
+// included, and of the writes to tables WITHOUT ROWID, virtual tables and
+// views.
+fn track_effects<T, F>(db: &Database,
+                       stmt: &Statement,
+                       execute: F)
+                       -> Result<T, SQLite3Error>
+    where F: FnOnce() -> Result<T, SQLite3Error>
+{
+    let reported = reports_row_changes(db, stmt)?;
+    track_execution_effects(db, stmt, reported, execute)
+}
+
+// Like track_effects, for the batches that execute the same statement many
+// times and check the tables it writes only once.
+fn track_execution_effects<T, F>(db: &Database,
+                                 stmt: &Statement,
+                                 reported: bool,
+                                 execute: F)
+                                 -> Result<T, SQLite3Error>
+    where F: FnOnce() -> Result<T, SQLite3Error>
+{
+    if !REPLICATE_EFFECTS.load(Ordering::Relaxed) {
+        return execute();
+    }
+    let version = schema_version(&db.connection)?;
+    db.effects.borrow_mut().rows.clear();
+    let result = execute()?;
+    let rows = mem::replace(&mut db.effects.borrow_mut().rows, Vec::new());
+
+    let mut effects = Vec::new();
+    if !reported || schema_version(&db.connection)? != version {
+        effects.push(format!("{}\n;", expanded_sql(stmt)?));
+    } else {
+        // Only the last change of each row matters.
+        let mut seen = HashSet::new();
+        for change in rows.iter().rev() {
+            if seen.insert(change) {
+                effects.push(row_effect(&db.connection, change)?);
+            }
+        }
+        effects.reverse();
+    }
+    db.effects.borrow_mut().pending.extend(effects);
+    Ok(result)
+}
+
+// Whether the update hook reports every row the statement writes. The
+// statement is compiled again so that the authorizer lists the tables it
+// writes.
+fn reports_row_changes(db: &Database,
+                       stmt: &Statement)
+                       -> Result<bool, SQLite3Error> {
+    if !REPLICATE_EFFECTS.load(Ordering::Relaxed) ||
+       unsafe { ffi::sqlite3_stmt_readonly(stmt.stmt) } != 0 {
+        return Ok(true);
+    }
+    db.effects.borrow_mut().targets.clear();
+    let sql = unsafe { CStr::from_ptr(ffi::sqlite3_sql(stmt.stmt)) };
+    create_statement(&db.connection, sql.to_string_lossy().into_owned())?;
+    let targets = mem::replace(&mut db.effects.borrow_mut().targets,
+                               HashSet::new());
+    for &(ref database, ref table) in &targets {
+        if !is_rowid_table(&db.connection, database, table)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// False for the tables WITHOUT ROWID, the virtual tables and the views.
+fn is_rowid_table(conn: &RawConnection,
+                  database: &str,
+                  table: &str)
+                  -> Result<bool, SQLite3Error> {
+    let query = format!("SELECT sql FROM {}.sqlite_master WHERE type = \
+                         'table' AND name = '{}';",
+                        quote_identifier(database),
+                        table.replace("'", "''"));
+    let stmt = create_statement(conn, query)?;
+    match unsafe { ffi::sqlite3_step(stmt.stmt) } {
+        ffi::SQLITE_ROW => {}
+        ffi::SQLITE_DONE => return Ok(false),
+        _ => return Err(SQLite3Error::from_statement(&stmt)),
+    }
+    let sql = String::from_utf8_lossy(&column_text_bytes(&stmt, 0))
+        .to_uppercase();
+    if sql.starts_with("CREATE VIRTUAL TABLE") {
+        return Ok(false);
+    }
+    let select = format!("SELECT rowid FROM {}.{} LIMIT 0;",
+                         quote_identifier(database),
+                         quote_identifier(table));
+    Ok(create_statement(conn, select).is_ok())
+}
+
+// The SQL of the statement with the values of its parameters in place of
+// the markers.
+fn expanded_sql(stmt: &Statement) -> Result<String, SQLite3Error> {
+    unsafe {
+        let sql = ffi::sqlite3_expanded_sql(stmt.stmt);
+        if sql.is_null() {
+            return Err(SQLite3Error::new(ffi::SQLITE_NOMEM,
+                                         String::from("cannot expand the \
+                                                       parameters of the \
+                                                       statement")));
+        }
+        let expanded = CStr::from_ptr(sql).to_string_lossy().into_owned();
+        ffi::sqlite3_free(sql as *mut std::os::raw::c_void);
+        Ok(expanded)
+    }
+}
+
+// Once no transaction is open anymore, the effects of the writes are
+// replicated as a single script, that REDISQL.APPLY_EFFECTS runs in a
+// savepoint.
+fn take_effects(db: &Database) -> Option<String> {
+    if unsafe { ffi::sqlite3_get_autocommit(db.connection.db) } == 0 {
+        return None;
+    }
+    let pending = mem::replace(&mut db.effects.borrow_mut().pending,
+                               Vec::new());
+    if pending.is_empty() {
+        return None;
+    }
+    let mut script = String::new();
+    for effect in pending {
+        script.push_str(&effect);
+        script.push('\n');
+    }
+    Some(script)
+}
+
+#[repr(C)]
+struct db_connection {
+    db: Arc<Mutex<Database>>,
+    // The file backing the database, None for in memory databases.
+    path: Option<String>,
+    // The commands waiting for a worker, the ones already queued are still
+    // served after the connection has been dropped.
+    queue: Arc<CommandQueue>,
+    // Interrupts the command running on the database, reachable without
+    // locking it, see lock_to_save.
+    killed: Arc<AtomicBool>,
+}
+
+fn create_db_connection(database: Database,
+                        path: Option<String>)
+                        -> db_connection {
+    let killed = database.timeout.killed.clone();
+    let db = Arc::new(Mutex::new(database));
+    let queue = Arc::new(CommandQueue {
+        db: db.clone(),
+        pending: Mutex::new(PendingCommands {
+            commands: VecDeque::new(),
+            scheduled: false,
+        }),
+        key: Mutex::new(None),
+    });
+    watch_keyspace(&queue);
+    db_connection {
+        db: db,
+        path: path,
+        queue: queue,
+        killed: killed,
+    }
+}
+
+struct BlockedClient {
+    client: *mut ffi::RedisModuleBlockedClient,
+}
+
+// The blocked client is only ever unblocked, which Redis allows from any
+// thread.
+unsafe impl Send for BlockedClient {}
+
+enum QueryResult {
+    OK,
+    DONE,
+    // The header rows, if asked, are not among the rows.
+    Rows {
+        columns: Vec<Column>,
+        headers: Headers,
+        rows: Vec<Row>,
+    },
+    // The statement has columns but did not return any row.
+    NoRows {
+        columns: Vec<Column>,
+        headers: Headers,
+    },
+    // The statement is on its first row, the rows are read while they are
+    // sent to the client.
+    Stream {
+        statement: StatementRef,
+        headers: Headers,
+    },
+    // The rows of a stream have already been sent, see stream_rows.
+    Streamed,
+    Cursor { id: usize },
+    Changes {
+        changes: i64,
+        last_insert_rowid: Option<i64>,
+    },
+}
+
+enum StatementRef {
+    Owned(Statement),
+    Named(String),
+}
+
+type CommandResult = Result<QueryResult, String>;
+
+// A named statement is reset once its rows are sent, so that it can run
+// again. Owned statements are simply dropped.
+fn release_statement(db: &Database, statement: &StatementRef) {
+    if let StatementRef::Named(ref name) = *statement {
+        if let Some(&(_, ref stmt)) = db.statements.get(name) {
+            reset_statement(stmt);
+        }
+    }
+}
+
+impl BlockedClient {
+    fn unblock(self, result: CommandResult) {
+        let privdata = Box::into_raw(Box::new(result));
+        unsafe {
+            ffi::RedisModule_UnblockClient.unwrap()(self.client,
+                                                    privdata as
+                                                    *mut std::os::raw::c_void);
+        }
+    }
+}
+
+enum Action {
+    Exec {
+        query: String,
+        parameters: Vec<Parameter>,
+        options: QueryOptions,
+    },
+    ExecScript { script: String },
+    // The effects replicated by another server, see take_effects.
+    ApplyEffects { script: String },
+    // The parameters of all the rows, one after the other.
+    ExecBatch {
+        query: String,
+        columns: usize,
+        parameters: Vec<Parameter>,
+    },
+    ImportCsv {
+        table: String,
+        source: CsvSource,
+        header: bool,
+        delimiter: u8,
+    },
+    Query {
+        query: String,
+        parameters: Vec<Parameter>,
+        options: QueryOptions,
+    },
+    ExecStatement {
+        name: String,
+        parameters: Vec<Parameter>,
+        options: QueryOptions,
+    },
+    CreateStatement { name: String, query: String },
+    UpdateStatement { name: String, query: String },
+    DeleteStatement { name: String },
+    QueryCursor {
+        query: String,
+        parameters: Vec<Parameter>,
+        options: QueryOptions,
+    },
+    Fetch { cursor: usize, count: usize },
+    CloseCursor { cursor: usize },
+    Begin,
+    Commit,
+    Rollback,
+}
+
+// Where REDISQL.IMPORT_CSV takes the CSV from.
+enum CsvSource {
+    Data(Vec<u8>),
+    // The path of a file on the server.
+    File(String),
+}
+
+impl CsvSource {
+    fn read(self) -> Result<Vec<u8>, String> {
+        match self {
+            CsvSource::Data(data) => Ok(data),
+            CsvSource::File(path) => {
+                let mut data = Vec::new();
+                match fs::File::open(&path)
+                    .and_then(|mut file| file.read_to_end(&mut data)) {
+                    Ok(_) => Ok(data),
+                    Err(e) => {
+                        Err(format!("ERR - Error, cannot read {}: {}",
+                                    path,
+                                    e))
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Action {
+    // Milliseconds the action can run, 0 for no limit.
+    fn timeout(&self) -> u64 {
+        let requested = match *self {
+            Action::Exec { options, .. } |
+            Action::Query { options, .. } |
+            Action::ExecStatement { options, .. } |
+            Action::QueryCursor { options, .. } => options.timeout,
+            _ => None,
+        };
+        requested.unwrap_or(QUERY_TIMEOUT.load(Ordering::Relaxed) as u64)
+    }
+}
+
+impl Action {
+    // The SQL the action runs, None if it does not run any statement.
+    fn running_sql(&self, db: &Database) -> Option<String> {
+        match *self {
+            Action::Exec { ref query, .. } |
+            Action::ExecBatch { ref query, .. } |
+            Action::Query { ref query, .. } |
+            Action::QueryCursor { ref query, .. } => Some(query.clone()),
+            Action::ExecScript { ref script } |
+            Action::ApplyEffects { ref script } => Some(script.clone()),
+            Action::ImportCsv { ref table, .. } => {
+                Some(format!("IMPORT_CSV {}", table))
+            }
+            Action::ExecStatement { ref name, .. } => {
+                db.statements.get(name).map(|&(ref sql, _)| sql.clone())
+            }
+            Action::Fetch { cursor, .. } => {
+                db.cursors.get(&cursor).map(|cursor| {
+                    let sql = unsafe { ffi::sqlite3_sql(cursor.stmt.stmt) };
+                    unsafe { CStr::from_ptr(sql) }
+                        .to_string_lossy()
+                        .into_owned()
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Action {
+    // What reaches the replicas and the AOF once the action has been
+    // executed, None for the actions that do not write and for the ones
+    // whose effects are replicated instead.
+    fn replication(&self, args: Vec<Vec<u8>>) -> Option<Replication> {
+        let effects = REPLICATE_EFFECTS.load(Ordering::Relaxed);
+        match *self {
+            Action::CreateStatement { .. } |
+            Action::UpdateStatement { .. } |
+            Action::DeleteStatement { .. } => Some(args),
+            // With FILE the replicas get the content of the file and not
+            // its path, see read_csv_file.
+            Action::ImportCsv {
+                source: CsvSource::Data(ref data), header, delimiter, .. }
+                if !effects => {
+                let mut command = vec![b"REDISQL.IMPORT_CSV".to_vec(),
+                                       args[1].clone(),
+                                       args[2].clone(),
+                                       data.clone()];
+                if header {
+                    command.push(b"HEADER".to_vec());
+                }
+                command.push(b"DELIMITER".to_vec());
+                command.push(vec![delimiter]);
+                Some(command)
+            }
+            Action::Exec { .. } |
+            Action::ExecScript { .. } |
+            Action::ApplyEffects { .. } |
+            Action::ExecBatch { .. } |
+            Action::ExecStatement { .. } |
+            Action::Begin |
+            Action::Commit if !effects => Some(args),
+            // The transactions rolled back are never propagated.
+            _ => None,
+        }
+    }
+}
+
+impl Action {
+    // The file of REDISQL.IMPORT_CSV ... FILE is read by the worker, before
+    // the database is locked.
+    fn read_csv_file(self) -> Result<Action, String> {
+        match self {
+            Action::ImportCsv { table, source, header, delimiter } => {
+                Ok(Action::ImportCsv {
+                    table: table,
+                    source: CsvSource::Data(source.read()?),
+                    header: header,
+                    delimiter: delimiter,
+                })
+            }
+            action => Ok(action),
+        }
+    }
+}
+
+// The name of a command followed by its arguments.
+type Replication = Vec<Vec<u8>>;
+
+struct Command {
+    action: Action,
+    client: BlockedClient,
+    // As sent by the client, the key is the second one.
+    args: Vec<Vec<u8>>,
+    client_id: u64,
+    // The protocol of the client, for the rows streamed by the worker.
+    resp3: bool,
+}
+
+// What the workers take from the queue of a database.
+enum Job {
+    Command(Command),
+    // The client disconnected, its transaction is rolled back if still open.
+    Disconnected(u64),
+}
+
+// A statement being executed, see REDISQL.PROCESSLIST and REDISQL.KILL.
+struct Process {
+    key: String,
+    client_id: u64,
+    sql: String,
+    started: Instant,
+    // Checked by the progress handler of the connection running the
+    // process, only this process is interrupted.
+    killed: Arc<AtomicBool>,
+}
+
+lazy_static! {
+    static ref PROCESSES: Mutex<HashMap<usize, Process>> =
+        Mutex::new(HashMap::new());
+}
+
+static PROCESS_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+fn register_process(key: &str,
+                    client_id: u64,
+                    sql: String,
+                    killed: &Arc<AtomicBool>)
+                    -> usize {
+    let id = PROCESS_COUNTER.fetch_add(1, Ordering::Relaxed);
+    PROCESSES.lock().unwrap().insert(id,
+                                     Process {
+                                         key: String::from(key),
+                                         client_id: client_id,
+                                         sql: sql,
+                                         started: Instant::now(),
+                                         killed: killed.clone(),
+                                     });
+    id
+}
+
+// Whether the process was killed while it was running.
+fn unregister_process(id: usize) -> bool {
+    match PROCESSES.lock().unwrap().remove(&id) {
+        Some(process) => process.killed.load(Ordering::SeqCst),
+        None => false,
+    }
+}
+
+// Run the action on the locked database, from a worker or, for the
+// clients that cannot be blocked, from the main thread. The replication of
+// the command is queued in the outbox if the command succeeded, or if it
+// failed after having changed the database: the replicas then stop at the
+// same error. Inside a transaction it waits for the COMMIT. The effects,
+// when they are replicated, are queued once the transaction is over. The
+// process of a streamed result is returned with it, it stays registered
+// until the rows are sent.
+fn execute_command(db: &mut Database,
+                   action: Action,
+                   key: &[u8],
+                   client_id: u64,
+                   replication: Option<Replication>)
+                   -> (CommandResult, Option<usize>) {
+    expire_cursors(db);
+    expire_transaction(db);
+    let version = replication.as_ref().map(|_| database_version(db));
+    db.timeout.start(action.timeout());
+    let sql = action.running_sql(db);
+    let (result, process) =
+        match check_transaction(db, client_id, sql.is_some()) {
+            Ok(()) => {
+                let process = sql.map(|sql| {
+                    register_process(&String::from_utf8_lossy(key),
+                                     client_id,
+                                     sql,
+                                     &db.timeout.killed)
+                });
+                (run_action(db, action, client_id), process)
+            }
+            Err(e) => (Err(e), None),
+        };
+    // The transaction may also end with an error that rolls it back.
+    if unsafe { ffi::sqlite3_get_autocommit(db.connection.db) } != 0 {
+        db.transaction = None;
+    }
+    let (killed, process) = match result {
+        Ok(QueryResult::Stream { .. }) => (false, process),
+        _ => (process.map_or(false, unregister_process), None),
+    };
+    let result = match result {
+        Err(_) if killed => {
+            Err(String::from("ERR - Error, the query was killed"))
+        }
+        Err(_) if db.timeout.expired.get() => Err(db.timeout.error()),
+        result => result,
+    };
+    // The deadline holds until the rows are streamed.
+    match result {
+        Ok(QueryResult::Stream { .. }) => {}
+        _ => db.timeout.stop(),
+    }
+    if let Some(replication) = replication {
+        if result.is_ok() || version != Some(database_version(db)) {
+            match db.transaction {
+                Some(ref mut transaction)
+                    if transaction.client_id == client_id => {
+                    transaction.replication.push(replication)
+                }
+                _ => db.outbox.push(replication),
+            }
+        }
+    }
+    if let Some(script) = take_effects(db) {
+        db.outbox.push(vec![b"REDISQL.APPLY_EFFECTS".to_vec(),
+                            key.to_vec(),
+                            script.into_bytes()]);
+    }
+    (result, process)
+}
+
+// Changes whenever a statement writes a row or alters the schema.
+fn database_version(db: &Database) -> (i32, Option<i64>) {
+    let changes = unsafe { ffi::sqlite3_total_changes(db.connection.db) };
+    (changes, schema_version(&db.connection).ok())
+}
+
+// Propagate the writes queued in the outbox, the Redis lock must be held.
+// They are replicated to where the key is now, it may have been renamed or
+// moved to another database since they were executed. Once the key is
+// deleted they are dropped, the replicas have already deleted it too.
+fn replicate_outbox(ctx: *mut ffi::RedisModuleCtx,
+                    key: &Mutex<Option<KeyName>>,
+                    db: &mut Database) {
+    let key = match *key.lock().unwrap() {
+        Some(ref key) => key.clone(),
+        None => {
+            db.outbox.clear();
+            return;
+        }
+    };
+    let selected = unsafe { ffi::RedisModule_GetSelectedDb.unwrap()(ctx) };
+    unsafe {
+        ffi::RedisModule_SelectDb.unwrap()(ctx, key.db);
+    }
+    for mut command in db.outbox.drain(..) {
+        command[1] = key.name.clone();
+        replicate(ctx, &command);
+    }
+    unsafe {
+        ffi::RedisModule_SelectDb.unwrap()(ctx, selected);
+    }
+}
+
+fn replicate(ctx: *mut ffi::RedisModuleCtx, command: &Replication) {
+    let name = CString::new(command[0].clone()).unwrap();
+    let fmt = CString::new("v").unwrap();
+    unsafe {
+        let args: Vec<*mut ffi::RedisModuleString> = command[1..]
+            .iter()
+            .map(|arg| {
+                ffi::RedisModule_CreateString.unwrap()(ctx,
+                                                       arg.as_ptr() as
+                                                       *const i8,
+                                                       arg.len())
+            })
+            .collect();
+        ffi::RedisModule_Replicate.unwrap()(ctx,
+                                            name.as_ptr(),
+                                            fmt.as_ptr(),
+                                            args.as_ptr(),
+                                            args.len());
+        for arg in args {
+            ffi::RedisModule_FreeString.unwrap()(ctx, arg);
+        }
+    }
+}
+
+// Take the Redis lock to propagate what the worker left in the outbox, the
+// client is replied only afterwards. The database is locked only once the
+// Redis lock is held.
+fn publish_outbox(queue: &CommandQueue, client: &BlockedClient) {
+    outside_sqlite(|| unsafe {
+        let ctx =
+            ffi::RedisModule_GetThreadSafeContext.unwrap()(client.client);
+        ffi::RedisModule_ThreadSafeContextLock.unwrap()(ctx);
+        replicate_outbox(ctx, &queue.key, &mut queue.db.lock().unwrap());
+        ffi::RedisModule_ThreadSafeContextUnlock.unwrap()(ctx);
+        ffi::RedisModule_FreeThreadSafeContext.unwrap()(ctx);
+    })
+}
+
+// The commands of a database, served in order by one worker at a time.
+struct CommandQueue {
+    db: Arc<Mutex<Database>>,
+    pending: Mutex<PendingCommands>,
+    // Only changed from the main thread, and read by the workers while
+    // holding the Redis lock: it is where the key is for the commands
+    // replicated meanwhile.
+    key: Mutex<Option<KeyName>>,
+}
+
+// Where the key of a database is: its name and the Redis database holding
+// it, None once it has been deleted.
+#[derive(Clone)]
+struct KeyName {
+    name: Vec<u8>,
+    db: i32,
+}
+
+struct PendingCommands {
+    commands: VecDeque<Job>,
+    // True while the queue is in READY or being served by a worker.
+    scheduled: bool,
+}
+
+impl CommandQueue {
+    // From the main thread, with the key the command was sent to.
+    fn set_key(&self, ctx: *mut ffi::RedisModuleCtx, name: &[u8]) {
+        let db = unsafe { ffi::RedisModule_GetSelectedDb.unwrap()(ctx) };
+        *self.key.lock().unwrap() = Some(KeyName {
+            name: name.to_vec(),
+            db: db,
+        });
+    }
+
+    fn push(queue: &Arc<CommandQueue>, job: Job) {
+        let mut pending = queue.pending.lock().unwrap();
+        pending.commands.push_back(job);
+        if !pending.scheduled {
+            pending.scheduled = true;
+            schedule(queue.clone());
+        }
+    }
+}
+
+// The commands used to have a thread per database, but there can be many
+// thousands of keys, and their threads would all compete for the CPU with
+// Redis. A small shared pool bounds the threads, while a queue per database
+// keeps the commands of each database in order. The size of the pool is set
+// with the WORKER_THREADS module option.
+static WORKER_THREADS: AtomicUsize = AtomicUsize::new(4);
+// Commands served from a queue before the worker moves to the next one, so
+// that a busy database does not starve the others.
+const WORKER_BATCH_COMMANDS: usize = 16;
+
+lazy_static! {
+    // The queues with commands waiting for a worker.
+    static ref READY: (Mutex<VecDeque<Arc<CommandQueue>>>, Condvar) =
+        (Mutex::new(VecDeque::new()), Condvar::new());
+}
+
+type ClientQueues = HashMap<u64, Vec<Weak<CommandQueue>>>;
+
+lazy_static! {
+    // The queues of the databases where a client opened a transaction, told
+    // when the client disconnects.
+    static ref CLIENT_TRANSACTIONS: Mutex<ClientQueues> =
+        Mutex::new(HashMap::new());
+}
+
+fn watch_disconnection(client_id: u64, queue: &Arc<CommandQueue>) {
+    let mut clients = CLIENT_TRANSACTIONS.lock().unwrap();
+    let queues = clients.entry(client_id).or_insert_with(Vec::new);
+    // The databases deleted meanwhile are forgotten.
+    queues.retain(|watched| watched.upgrade().is_some());
+    let watched = queues.iter().any(|watched| {
+        watched.upgrade().map_or(false, |watched| Arc::ptr_eq(&watched, queue))
+    });
+    if !watched {
+        queues.push(Arc::downgrade(queue));
+    }
+}
+
+// Subscribed to the client change events, called from the main thread. The
+// rollback is queued after the commands the client sent before leaving.
+unsafe extern "C" fn client_changed(_ctx: *mut ffi::RedisModuleCtx,
+                                    _event: ffi::RedisModuleEvent,
+                                    subevent: u64,
+                                    data: *mut std::os::raw::c_void) {
+    if subevent !=
+       ffi::REDISMODULE_SUBEVENT_CLIENT_CHANGE_DISCONNECTED as u64 {
+        return;
+    }
+    let client_id = (*(data as *const ffi::RedisModuleClientInfo)).id;
+    let queues = CLIENT_TRANSACTIONS.lock().unwrap().remove(&client_id);
+    for queue in queues.unwrap_or_default() {
+        if let Some(queue) = queue.upgrade() {
+            CommandQueue::push(&queue, Job::Disconnected(client_id));
+        }
+    }
+}
+
+lazy_static! {
+    // Every queue, SWAPDB changes where their keys are.
+    static ref QUEUES: Mutex<Vec<Weak<CommandQueue>>> =
+        Mutex::new(Vec::new());
+}
+
+fn watch_keyspace(queue: &Arc<CommandQueue>) {
+    let mut queues = QUEUES.lock().unwrap();
+    // The databases deleted meanwhile are forgotten.
+    queues.retain(|watched| watched.upgrade().is_some());
+    queues.push(Arc::downgrade(queue));
+}
+
+// Subscribed to the generic keyspace events. RENAME and MOVE notify where
+// the key went, in the context of the Redis database it is now in.
+unsafe extern "C" fn key_moved(ctx: *mut ffi::RedisModuleCtx,
+                               _type: std::os::raw::c_int,
+                               event: *const std::os::raw::c_char,
+                               key: *mut ffi::RedisModuleString)
+                               -> std::os::raw::c_int {
+    let event = CStr::from_ptr(event).to_bytes();
+    if event != &b"rename_to"[..] && event != &b"move_to"[..] {
+        return ffi::REDISMODULE_OK;
+    }
+    let redis_key = RedisKey {
+        key: ffi::Export_RedisModule_OpenKey(ctx, key, ffi::REDISMODULE_READ),
+    };
+    let key_type = ffi::RedisModule_ModuleTypeGetType.unwrap()(redis_key.key);
+    if key_type == ffi::DBType {
+        let db = ffi::RedisModule_ModuleTypeGetValue.unwrap()(redis_key.key) as
+                 *mut db_connection;
+        (*db).queue.set_key(ctx, &string_ptr_len_raw(key));
+    }
+    ffi::REDISMODULE_OK
+}
+
+// Subscribed to the SWAPDB server event, the keys of the two Redis
+// databases are exchanged.
+unsafe extern "C" fn databases_swapped(_ctx: *mut ffi::RedisModuleCtx,
+                                       _event: ffi::RedisModuleEvent,
+                                       _subevent: u64,
+                                       data: *mut std::os::raw::c_void) {
+    let swap = &*(data as *const ffi::RedisModuleSwapDbInfo);
+    let queues = QUEUES.lock().unwrap();
+    for queue in queues.iter().filter_map(|queue| queue.upgrade()) {
+        if let Some(ref mut key) = *queue.key.lock().unwrap() {
+            if key.db == swap.dbnum_first {
+                key.db = swap.dbnum_second;
+            } else if key.db == swap.dbnum_second {
+                key.db = swap.dbnum_first;
+            }
+        }
+    }
+}
+
+fn schedule(queue: Arc<CommandQueue>) {
+    let &(ref ready, ref wakeup) = &*READY;
+    ready.lock().unwrap().push_back(queue);
+    wakeup.notify_one();
+}
+
+// Redis writes the RDB and rewrites the AOF in a forked child, where only
+// the thread that forked exists. A worker in the middle of a command would
+// leave there the database locked forever and SQLite half way through a
+// write, so the fork waits for the commands being executed and the workers
+// do not use SQLite again until the fork is done. Redis is blocked
+// meanwhile: the wait is bounded, a command still running afterwards makes
+// the child fail the save, and Redis tries again later.
+struct ForkGuard {
+    forking: bool,
+    // Workers using SQLite.
+    active: usize,
+}
+
+lazy_static! {
+    static ref FORK_GUARD: (Mutex<ForkGuard>, Condvar) =
+        (Mutex::new(ForkGuard {
+            forking: false,
+            active: 0,
+        }),
+         Condvar::new());
+}
+
+// Set in the forked child.
+static FORKED: AtomicBool = AtomicBool::new(false);
+// Whether the last fork found the workers out of SQLite, read in the child.
+static FORK_QUIESCED: AtomicBool = AtomicBool::new(false);
+// How long a fork, or a save from the main thread, waits for the commands
+// being executed.
+const SAVE_WAIT_MILLISECONDS: u64 = 100;
+
+fn enter_sqlite() {
+    let &(ref guard, ref changed) = &*FORK_GUARD;
+    let mut guard = guard.lock().unwrap();
+    while guard.forking {
+        guard = changed.wait(guard).unwrap();
+    }
+    guard.active += 1;
+}
+
+fn leave_sqlite() {
+    let &(ref guard, ref changed) = &*FORK_GUARD;
+    let mut guard = guard.lock().unwrap();
+    guard.active -= 1;
+    changed.notify_all();
+}
+
+// For a worker waiting for the Redis lock: the main thread may be holding it
+// to fork.
+fn outside_sqlite<T, F>(f: F) -> T
+    where F: FnOnce() -> T
+{
+    leave_sqlite();
+    let result = f();
+    enter_sqlite();
+    result
+}
+
+unsafe extern "C" fn before_fork() {
+    let &(ref guard, ref changed) = &*FORK_GUARD;
+    let mut guard = guard.lock().unwrap();
+    guard.forking = true;
+    let deadline = Instant::now() +
+                   Duration::from_millis(SAVE_WAIT_MILLISECONDS);
+    while guard.active > 0 {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        guard = changed.wait_timeout(guard, deadline - now).unwrap().0;
+    }
+    FORK_QUIESCED.store(guard.active == 0, Ordering::SeqCst);
+}
+
+unsafe extern "C" fn after_fork_in_parent() {
+    let &(ref guard, ref changed) = &*FORK_GUARD;
+    guard.lock().unwrap().forking = false;
+    changed.notify_all();
+}
+
+unsafe extern "C" fn after_fork_in_child() {
+    FORKED.store(true, Ordering::SeqCst);
+}
+
+// Lock the database to save it. In the forked child nobody would ever
+// release the lock, and SQLite may have been left in the middle of a write:
+// if the fork did not find the workers out of SQLite nothing is saved. In
+// the main thread, SAVE or DEBUG RELOAD, the command holding the lock is
+// interrupted once it has been waited for long enough.
+fn lock_to_save<'a>(db: &'a db_connection)
+                    -> Option<MutexGuard<'a, Database>> {
+    if FORKED.load(Ordering::SeqCst) {
+        if !FORK_QUIESCED.load(Ordering::SeqCst) {
+            return None;
+        }
+        return db.db.try_lock().ok();
+    }
+    let deadline = Instant::now() +
+                   Duration::from_millis(SAVE_WAIT_MILLISECONDS);
+    loop {
+        match db.db.try_lock() {
+            Ok(database) => return Some(database),
+            Err(TryLockError::Poisoned(_)) => return None,
+            Err(TryLockError::WouldBlock) => {}
+        }
+        if Instant::now() >= deadline {
+            db.killed.store(true, Ordering::SeqCst);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn start_workers(threads: usize) {
+    for _ in 0..threads {
+        thread::spawn(worker_loop);
+    }
+}
+
+fn worker_loop() {
+    let &(ref ready, ref wakeup) = &*READY;
+    loop {
+        let queue = {
+            let mut ready = ready.lock().unwrap();
+            loop {
+                match ready.pop_front() {
+                    Some(queue) => break queue,
+                    None => ready = wakeup.wait(ready).unwrap(),
+                }
+            }
+        };
+        for _ in 0..WORKER_BATCH_COMMANDS {
+            let job = queue.pending.lock().unwrap().commands.pop_front();
+            let job = match job {
+                Some(job) => job,
+                None => break,
+            };
+            // A fork waits for one command at most, not for the batch.
+            enter_sqlite();
+            match job {
+                Job::Command(command) => serve_command(&queue, command),
+                Job::Disconnected(client_id) => {
+                    rollback_disconnected(&queue.db, client_id)
+                }
+            }
+            leave_sqlite();
+        }
+        {
+            let mut pending = queue.pending.lock().unwrap();
+            pending.scheduled = !pending.commands.is_empty();
+            if pending.scheduled {
+                schedule(queue.clone());
+            }
+        }
+        // The last reference to a database deleted meanwhile closes it.
+        enter_sqlite();
+        drop(queue);
+        leave_sqlite();
+    }
+}
+
+fn serve_command(queue: &CommandQueue, command: Command) {
+    let db = &queue.db;
+    let action = match command.action.read_csv_file() {
+        Ok(action) => action,
+        Err(error) => return command.client.unblock(Err(error)),
+    };
+    let key = command.args[1].clone();
+    let replication = action.replication(command.args);
+    let (result, process, replicated) = {
+        let mut db = db.lock().unwrap();
+        let (result, process) = execute_command(&mut db,
+                                                action,
+                                                &key,
+                                                command.client_id,
+                                                replication);
+        if let Ok(QueryResult::Stream {
+            statement: StatementRef::Named(ref name), .. }) = result {
+            db.streaming = Some(name.clone());
+        }
+        (result, process, !db.outbox.is_empty())
+    };
+    if replicated {
+        publish_outbox(queue, &command.client);
+    }
+    let result = match result {
+        Ok(QueryResult::Stream { statement, headers }) => {
+            stream_rows(db,
+                        &command.client,
+                        statement,
+                        headers,
+                        command.resp3,
+                        process);
+            Ok(QueryResult::Streamed)
+        }
+        result => result,
+    };
+    command.client.unblock(result);
+}
+
+// Rows stepped on the worker before they are written in the reply, the
+// Redis lock is taken once per batch.
+const STREAM_BATCH_ROWS: usize = 1000;
+
+// Send the rows of a QueryResult::Stream to the blocked client through a
+// thread safe context. The statement is stepped here, a batch at a time, and
+// every batch is written in the reply of the client while holding the Redis
+// lock. The database is never locked while waiting for the Redis lock, the
+// main thread may be waiting for it. An error in the middle of the rows is
+// sent as the last element of the array.
+fn stream_rows(db: &Mutex<Database>,
+               client: &BlockedClient,
+               statement: StatementRef,
+               headers: Headers,
+               resp3: bool,
+               process: Option<usize>) {
+    let ctx = unsafe {
+        ffi::RedisModule_GetThreadSafeContext.unwrap()(client.client)
+    };
+    // Commands run from the main thread in between reset the deadline.
+    let deadline = db.lock().unwrap().timeout.deadline.get();
+    let mut first_batch = true;
+    let mut columns = vec![];
+    let mut len = 0;
+    loop {
+        let (rows, error, done) = {
+            let mut db = db.lock().unwrap();
+            let (rows, error, done) = {
+                let stmt = match statement {
+                    StatementRef::Owned(ref stmt) => Some(stmt),
+                    StatementRef::Named(ref name) => {
+                        if db.streaming.as_ref() == Some(name) {
+                            db.statements.get(name).map(|&(_, ref stmt)| stmt)
+                        } else {
+                            None
+                        }
+                    }
+                };
+                match stmt {
+                    Some(stmt) => {
+                        db.timeout.deadline.set(deadline);
+                        if first_batch {
+                            columns = result_columns(stmt);
+                        }
+                        let mut rows = if first_batch && !resp3 {
+                            header_rows(&columns, headers)
+                        } else {
+                            vec![]
+                        };
+                        let mut cursor = rows_cursor(stmt);
+                        rows.extend(cursor.by_ref().take(STREAM_BATCH_ROWS));
+                        let error =
+                            cursor.error().map(|e| stream_error(&db, e));
+                        let done = !cursor.has_rows();
+                        (rows, error, done)
+                    }
+                    None => {
+                        (vec![],
+                         Some(String::from("ERR - Error, the statement was \
+                                            used by another command while \
+                                            its rows were sent")),
+                         true)
+                    }
+                }
+            };
+            if done {
+                db.timeout.stop();
+                if let Some(process) = process {
+                    unregister_process(process);
+                }
+                release_statement(&db, &statement);
+                if let StatementRef::Named(_) = statement {
+                    db.streaming = None;
+                }
+            }
+            (rows, error, done)
+        };
+        outside_sqlite(|| {
+            unsafe {
+                ffi::RedisModule_ThreadSafeContextLock.unwrap()(ctx);
+                if first_batch {
+                    if resp3 {
+                        begin_resp3_result(ctx, &columns, headers, false);
+                    }
+                    let postponed =
+                        ffi::REDISMODULE_POSTPONED_ARRAY_LEN as i64;
+                    ffi::RedisModule_ReplyWithArray.unwrap()(ctx, postponed);
+                }
+            }
+            for row in rows {
+                reply_with_row(ctx, &columns, &row, resp3);
+                len += 1;
+            }
+            if let Some(ref error) = error {
+                reply_with_error(ctx, error);
+                len += 1;
+            }
+            unsafe {
+                if done {
+                    ffi::RedisModule_ReplySetArrayLength.unwrap()(ctx, len);
+                    if resp3 {
+                        end_resp3_result(ctx, 0, None);
+                    }
+                }
+                ffi::RedisModule_ThreadSafeContextUnlock.unwrap()(ctx);
+            }
+        });
+        if done {
+            break;
+        }
+        first_batch = false;
+    }
+    unsafe {
+        ffi::RedisModule_FreeThreadSafeContext.unwrap()(ctx);
+    }
+}
+
+fn run_action(db: &mut Database,
+              action: Action,
+              client_id: u64)
+              -> CommandResult {
+    // A named statement being streamed by a worker cannot be used at the
+    // same time, the stream ends.
+    match action {
+        Action::ExecStatement { ref name, .. } |
+        Action::UpdateStatement { ref name, .. } |
+        Action::DeleteStatement { ref name } => {
+            if db.streaming.as_ref() == Some(name) {
+                db.streaming = None;
+            }
+        }
+        _ => {}
+    }
+    match action {
+        Action::Exec { query, parameters, options } => {
+            exec_query(db, query, &parameters, options)
+        }
+        Action::ExecScript { script } => exec_script(db, script),
+        Action::ApplyEffects { script } => apply_effects(db, script),
+        Action::ExecBatch { query, columns, parameters } => {
+            exec_batch(db, query, columns, &parameters)
+        }
+        Action::ImportCsv { table, source, header, delimiter } => {
+            source.read().and_then(|data| {
+                import_csv(db, table, data, header, delimiter)
+            })
+        }
+        Action::Query { query, parameters, options } => {
+            exec_read_only_query(db, query, &parameters, options)
+        }
+        Action::ExecStatement { name, parameters, options } => {
+            exec_named_statement(db, &name, &parameters, options)
+        }
+        Action::CreateStatement { name, query } => {
+            create_named_statement(db, name, query)
+        }
+        Action::UpdateStatement { name, query } => {
+            update_named_statement(db, name, query)
+        }
+        Action::DeleteStatement { name } => delete_named_statement(db, &name),
+        Action::QueryCursor { query, parameters, options } => {
+            open_cursor(db, query, &parameters, options)
+        }
+        Action::Fetch { cursor, count } => fetch_cursor(db, cursor, count),
+        Action::CloseCursor { cursor } => close_cursor(db, cursor),
+        Action::Begin => begin_transaction(db, client_id),
+        Action::Commit => end_transaction(db, client_id, "COMMIT;"),
+        Action::Rollback => end_transaction(db, client_id, "ROLLBACK;"),
+    }
+}
+
+// While a client has a transaction open the statements of the other clients
+// are refused, they would run inside it.
+fn check_transaction(db: &mut Database,
+                     client_id: u64,
+                     runs_sql: bool)
+                     -> Result<(), String> {
+    match db.transaction {
+        Some(ref mut transaction) if transaction.client_id == client_id => {
+            transaction.last_used = Instant::now();
+            Ok(())
+        }
+        Some(_) if runs_sql => {
+            Err(String::from("ERR - Error, another client has a transaction \
+                              open on this database"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn exec_simple(conn: &RawConnection, sql: &str) -> Result<(), SQLite3Error> {
+    let stmt = create_statement(conn, String::from(sql))?;
+    execute_statement(&stmt).map(|_| ())
+}
+
+fn begin_transaction(db: &mut Database, client_id: u64) -> CommandResult {
+    match db.transaction {
+        Some(ref transaction) if transaction.client_id == client_id => {
+            return Err(String::from("ERR - Error, a transaction is already \
+                                     open"));
+        }
+        Some(_) => {
+            return Err(String::from("ERR - Error, another client has a \
+                                     transaction open on this database"));
+        }
+        None => {}
+    }
+    let committed = open_connection(String::from(":memory:"))
+        .and_then(|committed| {
+            backup_connection(&db.connection, &committed).map(|_| committed)
+        })
+        .map_err(error_reply)?;
+    exec_simple(&db.connection, "BEGIN;").map_err(error_reply)?;
+    db.transaction = Some(Transaction {
+        client_id: client_id,
+        last_used: Instant::now(),
+        replication: Vec::new(),
+        committed: committed,
+    });
+    Ok(QueryResult::OK)
+}
+
+fn end_transaction(db: &mut Database,
+                   client_id: u64,
+                   sql: &str)
+                   -> CommandResult {
+    match db.transaction {
+        Some(ref transaction) if transaction.client_id == client_id => {}
+        _ => {
+            return Err(String::from("ERR - Error, no transaction open by \
+                                     this client"))
+        }
+    }
+    exec_simple(&db.connection, sql).map_err(error_reply)?;
+    if let Some(transaction) = db.transaction.take() {
+        if sql == "COMMIT;" {
+            db.outbox.extend(transaction.replication);
+        }
+    }
+    Ok(QueryResult::OK)
+}
+
+fn expire_transaction(db: &mut Database) {
+    let timeout = Duration::from_secs(TRANSACTION_IDLE_TIMEOUT);
+    let expired = match db.transaction {
+        Some(ref transaction) => transaction.last_used.elapsed() >= timeout,
+        None => false,
+    };
+    if expired {
+        abandon_transaction(db);
+    }
+}
+
+// The client that opened the transaction is gone, nobody would ever end it.
+fn rollback_disconnected(db: &Mutex<Database>, client_id: u64) {
+    let mut db = db.lock().unwrap();
+    let open = match db.transaction {
+        Some(ref transaction) => transaction.client_id == client_id,
+        None => false,
+    };
+    if open {
+        abandon_transaction(&mut db);
+    }
+}
+
+fn abandon_transaction(db: &mut Database) {
+    if let Err(e) = exec_simple(&db.connection, "ROLLBACK;") {
+        log_warning(&format!("Error rolling back an abandoned transaction: \
+                              {}",
+                             e));
+    }
+    db.transaction = None;
+}
+
+// What the first step of a statement gives, None if it gave a row: the rows
+// are then streamed to the client instead of being collected.
+fn first_result(cursor: Cursor) -> Option<QueryResult> {
+    match cursor {
+        Cursor::OKCursor => Some(QueryResult::OK),
+        Cursor::DONECursor => Some(QueryResult::DONE),
+        Cursor::RowsCursor { .. } => None,
+    }
+}
+
+// INSERT, UPDATE and DELETE reply with the number of rows they changed,
+// other statements without rows still reply with their columns, if they
+// have any.
+fn result_without_rows(conn: &RawConnection,
+                       stmt: &Statement,
+                       result: QueryResult,
+                       options: QueryOptions)
+                       -> QueryResult {
+    let has_columns = unsafe { ffi::sqlite3_column_count(stmt.stmt) } > 0;
+    match result {
+        QueryResult::DONE if changes_rows(stmt) => {
+            let last_insert_rowid = if options.last_insert_rowid {
+                Some(unsafe { ffi::sqlite3_last_insert_rowid(conn.db) })
+            } else {
+                None
+            };
+            QueryResult::Changes {
+                changes: unsafe { ffi::sqlite3_changes(conn.db) } as i64,
+                last_insert_rowid: last_insert_rowid,
+            }
+        }
+        QueryResult::DONE if has_columns => {
+            QueryResult::NoRows {
+                columns: result_columns(stmt),
+                headers: options.headers,
+            }
+        }
+        result => result,
+    }
+}
+
+// The first keyword of the statement, in upper case.
+fn first_keyword(stmt: &Statement) -> String {
+    let sql = unsafe { CStr::from_ptr(ffi::sqlite3_sql(stmt.stmt)) };
+    let mut sql: &str = &sql.to_string_lossy();
+    // Skip the comments before the first keyword.
+    loop {
+        sql = match sql.find(|c: char| !c.is_whitespace()) {
+            Some(start) => &sql[start..],
+            None => "",
+        };
+        if sql.starts_with("--") {
+            sql = match sql.find('\n') {
+                Some(end) => &sql[end..],
+                None => "",
+            };
+        } else if sql.starts_with("/*") {
+            sql = match sql.find("*/") {
+                Some(end) => &sql[end + 2..],
+                None => "",
+            };
+        } else {
+            break;
+        }
+    }
+    sql.chars()
+        .take_while(|c| c.is_alphabetic())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+// Whether the statement is an INSERT, UPDATE or DELETE, the only ones that
+// set sqlite3_changes. Other writes, like CREATE TABLE, leave there the count
+// of the previous statement.
+fn changes_rows(stmt: &Statement) -> bool {
+    if unsafe { ffi::sqlite3_stmt_readonly(stmt.stmt) } != 0 {
+        return false;
+    }
+    match first_keyword(stmt).as_str() {
+        // A WITH that writes can only be followed by a DML statement.
+        "INSERT" | "UPDATE" | "DELETE" | "REPLACE" | "WITH" => true,
+        _ => false,
+    }
+}
+
+// sqlite3_stmt_readonly is true for the statements that control the
+// transactions and for ATTACH and DETACH, since they do not write to the
+// database file by themselves, but they change the state of the connection
+// shared by every client.
+fn is_read_only(stmt: &Statement) -> bool {
+    if unsafe { ffi::sqlite3_stmt_readonly(stmt.stmt) } == 0 {
+        return false;
+    }
+    match first_keyword(stmt).as_str() {
+        "BEGIN" | "COMMIT" | "END" | "ROLLBACK" | "SAVEPOINT" | "RELEASE" |
+        "ATTACH" | "DETACH" => false,
+        _ => true,
+    }
+}
+
+// Transactions are opened and closed only by REDISQL.BEGIN, REDISQL.COMMIT
+// and REDISQL.ROLLBACK, that record the client owning them. The same
+// statements sent as SQL would leave a transaction that nobody owns.
+fn refuse_transaction_control(stmt: &Statement) -> Result<(), SQLite3Error> {
+    match first_keyword(stmt).as_str() {
+        "BEGIN" | "COMMIT" | "END" | "ROLLBACK" | "SAVEPOINT" | "RELEASE" => {
+            Err(SQLite3Error::new(ffi::SQLITE_MISUSE,
+                                  String::from("transactions are controlled \
+                                                with REDISQL.BEGIN, \
+                                                REDISQL.COMMIT and \
+                                                REDISQL.ROLLBACK")))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn collect_result(stmt: &Statement,
+                  result: Result<Cursor, SQLite3Error>)
+                  -> Result<QueryResult, SQLite3Error> {
+    match result {
+        Ok(Cursor::OKCursor) => Ok(QueryResult::OK),
+        Ok(Cursor::DONECursor) => Ok(QueryResult::DONE),
+        Ok(mut cursor) => {
+            let rows = cursor.by_ref().collect();
+            match cursor.error() {
+                Some(e) => Err(e),
+                None => {
+                    Ok(QueryResult::Rows {
+                        columns: result_columns(stmt),
+                        headers: Headers::Without,
+                        rows: rows,
+                    })
+                }
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn error_reply(error: SQLite3Error) -> String {
+    format!("ERR {}", error)
+}
+
+fn exec_query(db: &Database,
+              query: String,
+              parameters: &[Parameter],
+              options: QueryOptions)
+              -> CommandResult {
+    match create_statement(&db.connection, query)
+        .and_then(|stmt| refuse_transaction_control(&stmt).map(|_| stmt)) {
+        Ok(stmt) => {
+            let executed = track_effects(db, &stmt, || {
+                bind_parameters(&stmt, parameters)
+                    .and_then(|_| execute_statement(&stmt))
+                    .map(first_result)
+            });
+            match executed {
+                Ok(Some(result)) => {
+                    Ok(result_without_rows(&db.connection,
+                                           &stmt,
+                                           result,
+                                           options))
+                }
+                Ok(None) => {
+                    Ok(QueryResult::Stream {
+                        statement: StatementRef::Owned(stmt),
+                        headers: options.headers,
+                    })
+                }
+                Err(e) => Err(error_reply(e)),
+            }
+        }
+        Err(e) => Err(error_reply(e)),
+    }
+}
+
+// Like exec_query but refuses any statement that may modify the database, so
+// that it is safe to run on the replicas.
+fn exec_read_only_query(db: &Database,
+                        query: String,
+                        parameters: &[Parameter],
+                        options: QueryOptions)
+                        -> CommandResult {
+    match create_statement(&db.connection, query) {
+        Ok(stmt) => {
+            if !is_read_only(&stmt) {
+                return Err(String::from("ERR - Error, the statement is not \
+                                         read only, use REDISQL.EXEC"));
+            }
+            let executed = bind_parameters(&stmt, parameters)
+                .and_then(|_| execute_statement(&stmt))
+                .map(first_result);
+            match executed {
+                Ok(Some(result)) => {
+                    Ok(result_without_rows(&db.connection,
+                                           &stmt,
+                                           result,
+                                           options))
+                }
+                Ok(None) => {
+                    Ok(QueryResult::Stream {
+                        statement: StatementRef::Owned(stmt),
+                        headers: options.headers,
+                    })
+                }
+                Err(e) => Err(error_reply(e)),
+            }
+        }
+        Err(e) => Err(error_reply(e)),
+    }
+}
+
+// Run f inside a savepoint, so that everything it did is rolled back if it
+// fails. ROLLBACK TO does not call the rollback hook, the effects recorded
+// meanwhile are dropped here.
+fn with_savepoint<T, F>(db: &Database, f: F) -> Result<T, String>
+    where F: FnOnce() -> Result<T, String>
+{
+    let conn = &db.connection;
+    exec_simple(conn, "SAVEPOINT redisql;").map_err(error_reply)?;
+    let pending = db.effects.borrow().pending.len();
+    match f() {
+        Ok(result) => {
+            exec_simple(conn, "RELEASE redisql;").map_err(error_reply)?;
+            Ok(result)
+        }
+        Err(e) => {
+            db.effects.borrow_mut().pending.truncate(pending);
+            // An interrupted statement may have rolled back everything
+            // already, savepoint included.
+            let rollback = exec_simple(conn, "ROLLBACK TO redisql;")
+                .and_then(|_| exec_simple(conn, "RELEASE redisql;"));
+            if let Err(e) = rollback {
+                log_warning(&format!("Error rolling back to the \
+                                      savepoint: {}",
+                                     e));
+            }
+            Err(e)
+        }
+    }
+}
+
+// Execute the statement once for every row of parameters, all in a single
+// savepoint: either every row is applied or none is. Reply with the total
+// number of changed rows.
+fn exec_batch(db: &Database,
+              query: String,
+              columns: usize,
+              parameters: &[Parameter])
+              -> CommandResult {
+    let stmt = match create_statement(&db.connection, query)
+        .and_then(|stmt| refuse_transaction_control(&stmt).map(|_| stmt)) {
+        Ok(stmt) => stmt,
+        Err(e) => return Err(error_reply(e)),
+    };
+    with_savepoint(db, || {
+        let mut row_index = 0;
+        let executed = reports_row_changes(db, &stmt).and_then(|reported| {
+            let mut changes = 0;
+            for row in parameters.chunks(columns) {
+                row_index += 1;
+                track_execution_effects(db, &stmt, reported, || {
+                    bind_parameters(&stmt, row)
+                        .and_then(|_| execute_statement(&stmt).map(|_| ()))
+                })?;
+                changes += unsafe { ffi::sqlite3_changes(db.connection.db) } as
+                           i64;
+            }
+            Ok(changes)
+        });
+        reset_statement(&stmt);
+        match executed {
+            Ok(changes) => {
+                Ok(QueryResult::Changes {
+                    changes: changes,
+                    last_insert_rowid: None,
+                })
+            }
+            Err(e) => {
+                Err(format!("ERR row {} of the batch failed, {}",
+                            row_index,
+                            e))
+            }
+        }
+    })
+}
+
+type CsvRecord = Vec<Option<Vec<u8>>>;
+
+// Split the CSV in records as described by RFC 4180, both CRLF and LF end a
+// record. An empty field that is not quoted is a NULL, blank lines are
+// skipped.
+fn parse_csv(data: &[u8], delimiter: u8) -> Result<Vec<CsvRecord>, String> {
+    fn end_field(field: &mut Vec<u8>, quoted: &mut bool) -> Option<Vec<u8>> {
+        let value = if field.is_empty() && !*quoted {
+            None
+        } else {
+            Some(mem::replace(field, Vec::new()))
+        };
+        *quoted = false;
+        value
+    }
+
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = Vec::new();
+    let mut quoted = false;
+    let mut in_quotes = false;
+    let mut i = 0;
+    while i < data.len() {
+        let c = data[i];
+        if in_quotes {
+            if c != b'"' {
+                field.push(c);
+            } else if data.get(i + 1) == Some(&b'"') {
+                field.push(b'"');
+                i += 1;
+            } else {
+                in_quotes = false;
+            }
+        } else if c == b'"' && field.is_empty() && !quoted {
+            in_quotes = true;
+            quoted = true;
+        } else if c == delimiter {
+            record.push(end_field(&mut field, &mut quoted));
+        } else if c == b'\n' || c == b'\r' {
+            if c == b'\r' && data.get(i + 1) == Some(&b'\n') {
+                i += 1;
+            }
+            record.push(end_field(&mut field, &mut quoted));
+            if record.len() > 1 || record[0].is_some() {
+                records.push(record);
+            }
+            record = Vec::new();
+        } else {
+            field.push(c);
+        }
+        i += 1;
+    }
+    if in_quotes {
+        return Err(String::from("ERR - Error, the CSV ends inside a quoted \
+                                 field"));
+    }
+    if !record.is_empty() || !field.is_empty() || quoted {
+        record.push(end_field(&mut field, &mut quoted));
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// The narrowest type that fits every value of the column, the records too
+// short to have the column count as NULL.
+fn infer_column_type(records: &[CsvRecord], column: usize) -> &'static str {
+    let mut column_type = "INTEGER";
+    let mut has_values = false;
+    let values = records.iter()
+        .filter_map(|record| record.get(column).and_then(|v| v.as_ref()));
+    for value in values {
+        has_values = true;
+        let value = String::from_utf8_lossy(value);
+        if column_type == "INTEGER" && value.parse::<i64>().is_err() {
+            column_type = "REAL";
+        }
+        if column_type == "REAL" && value.parse::<f64>().is_err() {
+            return "TEXT";
+        }
+    }
+    if has_values { column_type } else { "TEXT" }
+}
+
+// Create the table, unless it exists already, and insert every record of the
+// CSV in a single savepoint. The values are bound as text and converted by
+// the affinity of the columns, like the sqlite3 shell does.
+fn import_csv(db: &Database,
+              table: String,
+              data: Vec<u8>,
+              header: bool,
+              delimiter: u8)
+              -> CommandResult {
+    let mut records = parse_csv(&data, delimiter)?;
+    let names = if header && !records.is_empty() {
+        Some(records.remove(0))
+    } else {
+        None
+    };
+    let columns = match (&names, records.first()) {
+        (&Some(ref names), _) => names.len(),
+        (&None, Some(record)) => record.len(),
+        (&None, None) => 0,
+    };
+    if columns == 0 {
+        return Ok(QueryResult::Changes {
+            changes: 0,
+            last_insert_rowid: None,
+        });
+    }
+    for (index, record) in records.iter().enumerate() {
+        if record.len() != columns {
+            return Err(format!("ERR - Error, row {} of the CSV has {} \
+                                fields instead of {}",
+                               index + 1,
+                               record.len(),
+                               columns));
+        }
+    }
+
+    let column_names: Vec<String> = match names {
+        Some(names) => {
+            names.into_iter()
+                .enumerate()
+                .map(|(i, name)| match name {
+                    Some(ref name) if !name.is_empty() => {
+                        String::from_utf8_lossy(name).into_owned()
+                    }
+                    _ => format!("c{}", i + 1),
+                })
+                .collect()
+        }
+        None => (1..columns + 1).map(|i| format!("c{}", i)).collect(),
+    };
+    let definitions: Vec<String> = column_names.iter()
+        .enumerate()
+        .map(|(i, name)| {
+            format!("{} {}",
+                    quote_identifier(name),
+                    infer_column_type(&records, i))
+        })
+        .collect();
+    let create = format!("CREATE TABLE IF NOT EXISTS {}({});",
+                         quote_identifier(&table),
+                         definitions.join(", "));
+    let placeholders = vec!["?"; columns].join(", ");
+    // Without a header the values go in the columns of the table in order.
+    let insert = if header {
+        let quoted_names: Vec<String> =
+            column_names.iter().map(|name| quote_identifier(name)).collect();
+        format!("INSERT INTO {}({}) VALUES ({});",
+                quote_identifier(&table),
+                quoted_names.join(", "),
+                placeholders)
+    } else {
+        format!("INSERT INTO {} VALUES ({});",
+                quote_identifier(&table),
+                placeholders)
+    };
+
+    with_savepoint(db, || {
+        let create = create_statement(&db.connection, create)
+            .map_err(error_reply)?;
+        track_effects(db, &create, || execute_statement(&create).map(|_| ()))
+            .map_err(error_reply)?;
+        let stmt = create_statement(&db.connection, insert)
+            .map_err(error_reply)?;
+        let mut row_index = 0;
+        let executed = reports_row_changes(db, &stmt).and_then(|reported| {
+            for record in records {
+                row_index += 1;
+                let parameters: Vec<Parameter> = record.into_iter()
+                    .map(|value| match value {
+                        Some(text) => Parameter::Text { text: text },
+                        None => Parameter::Null,
+                    })
+                    .collect();
+                track_execution_effects(db, &stmt, reported, || {
+                    bind_parameters(&stmt, &parameters)
+                        .and_then(|_| execute_statement(&stmt).map(|_| ()))
+                })?;
+            }
+            Ok(())
+        });
+        reset_statement(&stmt);
+        match executed {
+            Ok(()) => {
+                Ok(QueryResult::Changes {
+                    changes: row_index,
+                    last_insert_rowid: None,
+                })
+            }
+            Err(e) => {
+                Err(format!("ERR row {} of the CSV failed, {}", row_index, e))
+            }
+        }
+    })
+}
+
+// Execute, in order, every statement of the script and return the result of
+// the last one. The execution stops at the first statement that fails.
+fn exec_script(db: &Database, script: String) -> CommandResult {
+    let mut rest: &str = &script;
+    let mut result = QueryResult::DONE;
+    let mut index = 0;
+    while !rest.is_empty() {
+        let (stmt, consumed) =
+            match create_statement_with_tail(&db.connection, rest) {
+                Ok(compiled) => compiled,
+                Err(e) => {
+                    return Err(format!("ERR statement {} of the script \
+                                        failed, {}",
+                                       index + 1,
+                                       e))
+                }
+            };
+        if let Some(stmt) = stmt {
+            index += 1;
+            let executed = refuse_transaction_control(&stmt)
+                .and_then(|_| {
+                    track_effects(db, &stmt, || {
+                        collect_result(&stmt, execute_statement(&stmt))
+                    })
+                });
+            result = match executed {
+                Ok(result) => {
+                    result_without_rows(&db.connection,
+                                        &stmt,
+                                        result,
+                                        QueryOptions::default())
+                }
+                Err(e) => {
+                    return Err(format!("ERR statement {} of the script \
+                                        failed, {}",
+                                       index,
+                                       e))
+                }
+            };
+        }
+        if consumed == 0 {
+            break;
+        }
+        rest = &rest[consumed..];
+    }
+    Ok(result)
+}
+
+// The effects are applied all together or not at all, like the writes
+// that caused them. They already hold what the triggers and the foreign key
+// actions did on the master, neither runs again. Foreign keys cannot be
+// turned off inside a transaction, hence before the savepoint.
+fn apply_effects(db: &Database, script: String) -> CommandResult {
+    let conn = &db.connection;
+    let triggers = db_config(conn, ffi::SQLITE_DBCONFIG_ENABLE_TRIGGER, -1);
+    let foreign_keys = db_config(conn, ffi::SQLITE_DBCONFIG_ENABLE_FKEY, -1);
+    db_config(conn, ffi::SQLITE_DBCONFIG_ENABLE_TRIGGER, 0);
+    db_config(conn, ffi::SQLITE_DBCONFIG_ENABLE_FKEY, 0);
+    let result = with_savepoint(db, || exec_script(db, script));
+    db_config(conn, ffi::SQLITE_DBCONFIG_ENABLE_FKEY, foreign_keys);
+    db_config(conn, ffi::SQLITE_DBCONFIG_ENABLE_TRIGGER, triggers);
+    result
+}
+
+// Set one of the SQLITE_DBCONFIG_ENABLE_ options to 1 or 0, or leave it as
+// it is with -1. It returns the option as it is afterwards.
+fn db_config(conn: &RawConnection, option: i32, value: i32) -> i32 {
+    let mut state: i32 = 0;
+    unsafe {
+        ffi::sqlite3_db_config(conn.db, option, value, &mut state);
+    }
+    state
+}
+
+fn exec_named_statement(db: &Database,
+                        name: &str,
+                        parameters: &[Parameter],
+                        options: QueryOptions)
+                        -> CommandResult {
+    match db.statements.get(name) {
+        Some(&(_, ref stmt)) => {
+            let executed = track_effects(db, stmt, || {
+                bind_parameters(stmt, parameters)
+                    .and_then(|_| execute_statement(stmt))
+                    .map(first_result)
+            });
+            match executed {
+                Ok(Some(result)) => {
+                    reset_statement(stmt);
+                    Ok(result_without_rows(&db.connection,
+                                           stmt,
+                                           result,
+                                           options))
+                }
+                // Reset once the rows are sent.
+                Ok(None) => {
+                    Ok(QueryResult::Stream {
+                        statement: StatementRef::Named(String::from(name)),
+                        headers: options.headers,
+                    })
+                }
+                Err(e) => {
+                    reset_statement(stmt);
+                    Err(error_reply(e))
+                }
+            }
+        }
+        None => Err(String::from("ERR - Error, no statement with this name")),
+    }
+}
+
+fn create_named_statement(db: &mut Database,
+                          name: String,
+                          query: String)
+                          -> CommandResult {
+    if db.statements.contains_key(&name) {
+        return Err(String::from("ERR - Error, a statement with the same \
+                                 name already exists"));
+    }
+    match create_statement(&db.connection, query.clone())
+        .and_then(|stmt| refuse_transaction_control(&stmt).map(|_| stmt)) {
+        Ok(stmt) => {
+            db.statements.insert(name, (query, stmt));
+            Ok(QueryResult::OK)
+        }
+        Err(e) => Err(error_reply(e)),
+    }
+}
+
+fn update_named_statement(db: &mut Database,
+                          name: String,
+                          query: String)
+                          -> CommandResult {
+    if !db.statements.contains_key(&name) {
+        return Err(String::from("ERR - Error, no statement with this name"));
+    }
+    match create_statement(&db.connection, query.clone())
+        .and_then(|stmt| refuse_transaction_control(&stmt).map(|_| stmt)) {
+        Ok(stmt) => {
+            db.statements.insert(name, (query, stmt));
+            Ok(QueryResult::OK)
+        }
+        Err(e) => Err(error_reply(e)),
+    }
+}
+
+fn delete_named_statement(db: &mut Database, name: &str) -> CommandResult {
+    match db.statements.remove(name) {
+        Some(_) => Ok(QueryResult::OK),
+        None => Err(String::from("ERR - Error, no statement with this name")),
+    }
+}
+
+fn open_cursor(db: &mut Database,
+               query: String,
+               parameters: &[Parameter],
+               options: QueryOptions)
+               -> CommandResult {
+    let stmt = match create_statement(&db.connection, query) {
+        Ok(stmt) => stmt,
+        Err(e) => return Err(error_reply(e)),
+    };
+    if !is_read_only(&stmt) {
+        return Err(String::from("ERR - Error, the statement is not read \
+                                 only, use REDISQL.EXEC"));
+    }
+    let has_rows = match bind_parameters(&stmt, parameters)
+        .and_then(|_| execute_statement(&stmt)) {
+        Ok(cursor) => cursor.has_rows(),
+        Err(e) => return Err(error_reply(e)),
+    };
+    let id = CURSOR_COUNTER.fetch_add(1, Ordering::Relaxed);
+    db.cursors.insert(id,
+                      ServerCursor {
+                          stmt: stmt,
+                          has_rows: has_rows,
+                          headers: options.headers,
+                          last_used: Instant::now(),
+                      });
+    Ok(QueryResult::Cursor { id: id })
+}
+
+// Reply with, at most, the next count rows of the cursor. The first fetch
+// returning less than count rows closes the cursor, so a last page that is
+// full is followed by an empty one.
+fn fetch_cursor(db: &mut Database, id: usize, count: usize) -> CommandResult {
+    let (rows, error) = match db.cursors.get_mut(&id) {
+        Some(cursor) => {
+            cursor.last_used = Instant::now();
+            if cursor.has_rows {
+                let mut page = rows_cursor(&cursor.stmt);
+                let rows: Vec<Row> = page.by_ref().take(count).collect();
+                cursor.has_rows = page.has_rows();
+                (rows, page.error())
+            } else {
+                (vec![], None)
+            }
+        }
+        None => {
+            return Err(String::from("ERR - Error, no cursor with this id"))
+        }
+    };
+    let (columns, headers) = match db.cursors.get(&id) {
+        Some(cursor) => (result_columns(&cursor.stmt), cursor.headers),
+        None => (vec![], Headers::Without),
+    };
+    if rows.len() < count || error.is_some() {
+        db.cursors.remove(&id);
+    }
+    match error {
+        Some(e) => Err(error_reply(e)),
+        None => {
+            Ok(QueryResult::Rows {
+                columns: columns,
+                headers: headers,
+                rows: rows,
+            })
+        }
+    }
+}
+
+fn close_cursor(db: &mut Database, id: usize) -> CommandResult {
+    match db.cursors.remove(&id) {
+        Some(_) => Ok(QueryResult::OK),
+        None => Err(String::from("ERR - Error, no cursor with this id")),
+    }
+}
+
+fn expire_cursors(db: &mut Database) {
+    let timeout = Duration::from_secs(CURSOR_IDLE_TIMEOUT);
+    db.cursors.retain(|_, cursor| cursor.last_used.elapsed() < timeout);
+}
+
+fn reply_with_result(ctx: *mut ffi::RedisModuleCtx,
+                     result: &CommandResult)
+                     -> i32 {
+    let resp3 = speaks_resp3(ctx);
+    match *result {
+        Ok(QueryResult::OK) => reply_with_ok(ctx),
+        Ok(QueryResult::DONE) => reply_with_done(ctx),
+        Ok(QueryResult::Rows { ref columns, headers, ref rows }) => {
+            reply_with_rows(ctx, columns, headers, rows, resp3)
+        }
+        Ok(QueryResult::NoRows { ref columns, headers }) => {
+            if !resp3 && headers == Headers::Without {
+                reply_with_done(ctx)
+            } else {
+                reply_with_rows(ctx, columns, headers, &[], resp3)
+            }
+        }
+        Ok(QueryResult::Cursor { id }) => unsafe {
+            ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx, id as i64)
+        },
+        Ok(QueryResult::Changes { changes, last_insert_rowid }) if resp3 => {
+            begin_resp3_result(ctx,
+                               &[],
+                               Headers::Without,
+                               last_insert_rowid.is_some());
+            unsafe {
+                ffi::RedisModule_ReplyWithArray.unwrap()(ctx, 0);
+            }
+            end_resp3_result(ctx, changes, last_insert_rowid);
+            ffi::REDISMODULE_OK
+        }
+        Ok(QueryResult::Changes { changes, last_insert_rowid: None }) => {
+            unsafe { ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx, changes) }
+        }
+        Ok(QueryResult::Changes { changes,
+                                  last_insert_rowid: Some(rowid) }) => {
+            unsafe {
+                ffi::RedisModule_ReplyWithArray.unwrap()(ctx, 2);
+                ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx, changes);
+                ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx, rowid);
+            }
+            ffi::REDISMODULE_OK
+        }
+        Ok(QueryResult::Stream { .. }) => {
+            reply_with_error(ctx, "ERR - Error, the rows are not available")
+        }
+        Ok(QueryResult::Streamed) => ffi::REDISMODULE_OK,
+        Err(ref error) => reply_with_error(ctx, error),
+    }
+}
+
+fn reply_with_rows(ctx: *mut ffi::RedisModuleCtx,
+                   columns: &[Column],
+                   headers: Headers,
+                   rows: &[Row],
+                   resp3: bool)
+                   -> i32 {
+    if resp3 {
+        begin_resp3_result(ctx, columns, headers, false);
+        unsafe {
+            ffi::RedisModule_ReplyWithArray.unwrap()(ctx, rows.len() as i64);
+        }
+        for row in rows {
+            reply_with_row(ctx, columns, row, true);
+        }
+        end_resp3_result(ctx, 0, None);
+    } else {
+        let header = header_rows(columns, headers);
+        let len = header.len() + rows.len();
+        unsafe {
+            ffi::RedisModule_ReplyWithArray.unwrap()(ctx, len as i64);
+        }
+        for row in header.iter().chain(rows) {
+            reply_with_row(ctx, columns, row, false);
+        }
+    }
+    ffi::REDISMODULE_OK
+}
+
+// Why the statement stopped in the middle of its rows.
+fn stream_error(db: &Database, error: SQLite3Error) -> String {
+    if db.timeout.killed.load(Ordering::SeqCst) {
+        String::from("ERR - Error, the query was killed")
+    } else if db.timeout.expired.get() {
+        db.timeout.error()
+    } else {
+        error_reply(error)
+    }
+}
+
+// Reply with the rows while the statement is stepped, the length of the
+// array is only known at the end. An error in the middle of the rows is
+// sent as the last element of the array. Only for the commands run inline,
+// that are on the main thread anyway; the worker uses stream_rows.
+fn reply_with_stream(ctx: *mut ffi::RedisModuleCtx,
+                     db: &Database,
+                     statement: &StatementRef,
+                     headers: Headers)
+                     -> i32 {
+    let stmt = match *statement {
+        StatementRef::Owned(ref stmt) => stmt,
+        StatementRef::Named(ref name) => {
+            match db.statements.get(name) {
+                Some(&(_, ref stmt)) => stmt,
+                None => {
+                    return reply_with_error(ctx,
+                                            "ERR - Error, no statement with \
+                                             this name")
+                }
+            }
+        }
+    };
+    let resp3 = speaks_resp3(ctx);
+    let columns = result_columns(stmt);
+    if resp3 {
+        begin_resp3_result(ctx, &columns, headers, false);
+    }
+    unsafe {
+        ffi::RedisModule_ReplyWithArray
+            .unwrap()(ctx, ffi::REDISMODULE_POSTPONED_ARRAY_LEN as i64);
+    }
+    let header = if resp3 {
+        vec![]
+    } else {
+        header_rows(&columns, headers)
+    };
+    let mut cursor = rows_cursor(stmt);
+    let mut len = 0;
+    for row in header.into_iter().chain(cursor.by_ref()) {
+        reply_with_row(ctx, &columns, &row, resp3);
+        len += 1;
+    }
+    if let Some(e) = cursor.error() {
+        reply_with_error(ctx, &stream_error(db, e));
+        len += 1;
+    }
+    unsafe {
+        ffi::RedisModule_ReplySetArrayLength.unwrap()(ctx, len);
+    }
+    if resp3 {
+        end_resp3_result(ctx, 0, None);
+    }
+    ffi::REDISMODULE_OK
+}
+
+unsafe extern "C" fn reply_blocked_client(ctx: *mut ffi::RedisModuleCtx,
+                                          _argv: *mut *mut ffi::RedisModuleString,
+                                          _argc: ::std::os::raw::c_int)
+                                          -> i32 {
+    let privdata = ffi::RedisModule_GetBlockedClientPrivateData.unwrap()(ctx);
+    let result = &*(privdata as *mut CommandResult);
+    reply_with_result(ctx, result)
+}
+
+unsafe extern "C" fn free_blocked_client_result(privdata: *mut std::os::raw::c_void) {
+    drop(Box::from_raw(privdata as *mut CommandResult));
+}
+
+// Redis cannot block a client inside a MULTI or a Lua script, the master
+// sending the replication stream, or the fake client loading the AOF.
+// Servers too old to report the flags of the context, or to lend a context
+// to the workers, are never asked to block a client.
+fn can_block(ctx: *mut ffi::RedisModuleCtx) -> bool {
+    let unblockable = ffi::REDISMODULE_CTX_FLAGS_LUA |
+                      ffi::REDISMODULE_CTX_FLAGS_MULTI |
+                      ffi::REDISMODULE_CTX_FLAGS_REPLICATED |
+                      ffi::REDISMODULE_CTX_FLAGS_LOADING |
+                      ffi::REDISMODULE_CTX_FLAGS_DENY_BLOCKING;
+    if unsafe { ffi::RedisModule_GetThreadSafeContext }.is_none() {
+        return false;
+    }
+    match unsafe { ffi::RedisModule_GetContextFlags } {
+        Some(get_context_flags) => {
+            let flags = unsafe { get_context_flags(ctx) };
+            flags & unblockable == 0
+        }
+        None => false,
+    }
+}
+
+fn from_master_or_aof(ctx: *mut ffi::RedisModuleCtx) -> bool {
+    let flags = ffi::REDISMODULE_CTX_FLAGS_REPLICATED |
+                ffi::REDISMODULE_CTX_FLAGS_LOADING;
+    match unsafe { ffi::RedisModule_GetContextFlags } {
+        Some(get_context_flags) => {
+            let context_flags = unsafe { get_context_flags(ctx) };
+            context_flags & flags != 0
+        }
+        None => false,
+    }
+}
+
+// Run the action right away, on the main thread, for a client that cannot
+// be blocked. The rows of a streamed result are sent before the database is
+// unlocked.
+fn execute_inline(ctx: *mut ffi::RedisModuleCtx,
+                  db: &db_connection,
+                  action: Action,
+                  args: Vec<Vec<u8>>)
+                  -> i32 {
+    let action = match action.read_csv_file() {
+        Ok(action) => action,
+        Err(error) => return reply_with_error(ctx, &error),
+    };
+    let key = args[1].clone();
+    let replication = action.replication(args);
+    let client_id = unsafe { ffi::RedisModule_GetClientId.unwrap()(ctx) };
+    let mut database = db.db.lock().unwrap();
+    let (result, process) = execute_command(&mut database,
+                                            action,
+                                            &key,
+                                            client_id,
+                                            replication);
+    // The writes of a worker still waiting for the Redis lock go first.
+    replicate_outbox(ctx, &db.queue.key, &mut database);
+    match result {
+        Ok(QueryResult::Stream { statement, headers }) => {
+            let reply = reply_with_stream(ctx, &database, &statement, headers);
+            database.timeout.stop();
+            if let Some(process) = process {
+                unregister_process(process);
+            }
+            release_statement(&database, &statement);
+            reply
+        }
+        result => reply_with_result(ctx, &result),
+    }
+}
+
+// Block the client and queue the action for the workers, the client gets
+// its reply once a worker is done.
+fn send_to_worker(ctx: *mut ffi::RedisModuleCtx,
+                  argv: *mut *mut ffi::RedisModuleString,
+                  argc: ::std::os::raw::c_int,
+                  db: &db_connection,
+                  action: Action)
+                  -> i32 {
+    let args = parse_raw_args(argv, argc);
+    db.queue.set_key(ctx, &args[1]);
+    if !can_block(ctx) {
+        return execute_inline(ctx, db, action, args);
+    }
+    let client = BlockedClient {
+        client: unsafe {
+            ffi::RedisModule_BlockClient.unwrap()(ctx,
+                                                  Some(reply_blocked_client),
+                                                  None,
+                                                  Some(free_blocked_client_result),
+                                                  0)
+        },
+    };
+    let command = Command {
+        action: action,
+        client: client,
+        args: args,
+        client_id: unsafe { ffi::RedisModule_GetClientId.unwrap()(ctx) },
+        resp3: speaks_resp3(ctx),
+    };
+    CommandQueue::push(&db.queue, Job::Command(command));
+    ffi::REDISMODULE_OK
+}
+
+#[repr(C)]
+struct RedisKey {
+    key: *mut ffi::RedisModuleKey,
+}
+
+impl Drop for RedisKey {
+    fn drop(&mut self) {
+        println!("Key closed");
+        unsafe {
+            ffi::RedisModule_CloseKey.unwrap()(self.key);
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+extern "C" fn DeleteDB(ctx: *mut ffi::RedisModuleCtx,
+                       argv: *mut *mut ffi::RedisModuleString,
+                       argc: ::std::os::raw::c_int)
+                       -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+    match argvector.len() {
+        2 => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            if let Err(reply) = get_db_connection(ctx, &safe_key) {
+                return reply;
+            }
+            // Redis owns the database, deleting the key calls free_db.
+            match unsafe { ffi::RedisModule_DeleteKey.unwrap()(safe_key.key) } {
+                ffi::REDISMODULE_OK => {
+                    replicate_verbatim(ctx);
+                    reply_with_ok(ctx)
+                }
+                _ => reply_with_error(ctx, "ERR - Error deleting the key"),
+            }
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
+
+// For the errors that no client gets, they go in the Redis log.
+fn log_warning(message: &str) {
+    let level = CString::new("warning").unwrap();
+    let format = CString::new("%s").unwrap();
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    // Outside of Redis, in the tests, there is no log.
+    if let Some(log) = unsafe { ffi::RedisModule_Log } {
         unsafe {
-            ffi::RedisModule_CloseKey.unwrap()(self.key);
+            log(ptr::null_mut(),
+                level.as_ptr(),
+                format.as_ptr(),
+                message.as_ptr())
+        }
+    }
+}
+
+fn reply_with_error(ctx: *mut ffi::RedisModuleCtx, message: &str) -> i32 {
+    // The message may quote what the client sent, a NUL would end it.
+    let error = CString::new(message.replace('\0', "")).unwrap();
+    unsafe { ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr()) }
+}
+
+fn reply_with_ok(ctx: *mut ffi::RedisModuleCtx) -> i32 {
+    let ok = CString::new("OK").unwrap();
+    unsafe { ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, ok.as_ptr()) }
+}
+
+fn reply_with_done(ctx: *mut ffi::RedisModuleCtx) -> i32 {
+    let done = CString::new("DONE").unwrap();
+    unsafe {
+        ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, done.as_ptr())
+    }
+}
+
+// Return the database stored in the key or reply to the client with the
+// appropriate error.
+fn get_db_connection(ctx: *mut ffi::RedisModuleCtx,
+                     key: &RedisKey)
+                     -> Result<*mut db_connection, i32> {
+    let key_type = unsafe { ffi::RedisModule_KeyType.unwrap()(key.key) };
+    match key_type {
+        ffi::REDISMODULE_KEYTYPE_EMPTY => {
+            Err(reply_with_error(ctx, "ERR - Error the key is empty"))
+        }
+        _ if unsafe {
+            ffi::DBType != ffi::RedisModule_ModuleTypeGetType.unwrap()(key.key)
+        } => {
+            let error =
+                CStr::from_bytes_with_nul(ffi::REDISMODULE_ERRORMSG_WRONGTYPE)
+                    .unwrap();
+            Err(unsafe {
+                ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
+            })
+        }
+        _ => {
+            Ok(unsafe {
+                ffi::RedisModule_ModuleTypeGetValue.unwrap()(key.key) as
+                *mut db_connection
+            })
+        }
+    }
+}
+
+// Open the key given as first argument of the command, the name is passed
+// to Redis as the client sent it so that binary key names are kept intact.
+fn open_key(ctx: *mut ffi::RedisModuleCtx,
+            argv: *mut *mut ffi::RedisModuleString,
+            mode: i32)
+            -> RedisKey {
+    let key = unsafe {
+        ffi::Export_RedisModule_OpenKey(ctx, *argv.offset(1), mode)
+    };
+    RedisKey { key: key }
+}
+
+// The text of a query as the client sent it. SQLite reads statements as
+// UTF-8, a query that is not valid UTF-8 is refused rather than executed
+// with its invalid bytes replaced.
+fn query_argument(raw: &[u8]) -> Result<String, String> {
+    String::from_utf8(raw.to_vec())
+        .map_err(|_| String::from("ERR - The query is not valid UTF-8"))
+}
+
+// Write commands are propagated, as they are, to the replicas and to the
+// AOF.
+fn replicate_verbatim(ctx: *mut ffi::RedisModuleCtx) {
+    unsafe {
+        ffi::RedisModule_ReplicateVerbatim.unwrap()(ctx);
+    }
+}
+
+// The options given between the key and the query, and how many arguments
+// they take.
+fn parse_options(argvector: &[String]) -> (QueryOptions, usize) {
+    let mut options = QueryOptions::default();
+    let mut i = 2;
+    while i < argvector.len() {
+        match argvector[i].to_uppercase().as_str() {
+            "WITH_HEADERS" | "WITH_NAMES" => {
+                if options.headers == Headers::Without {
+                    options.headers = Headers::Names;
+                }
+            }
+            "WITH_TYPES" => options.headers = Headers::NamesAndTypes,
+            "WITH_LAST_INSERT_ROWID" => options.last_insert_rowid = true,
+            "TIMEOUT" => {
+                match argvector.get(i + 1)
+                    .and_then(|ms| ms.parse::<u64>().ok()) {
+                    Some(ms) => {
+                        options.timeout = Some(ms);
+                        i += 1;
+                    }
+                    None => break,
+                }
+            }
+            _ => break,
         }
+        i += 1;
     }
+    (options, i - 2)
+}
+
+fn parse_parameters(args: &[Vec<u8>]) -> Result<Vec<Parameter>, String> {
+    args.iter().map(|arg| parse_parameter(arg)).collect()
 }
 
 #[allow(non_snake_case)]
-extern "C" fn DeleteDB(ctx: *mut ffi::RedisModuleCtx,
-                       argv: *mut *mut ffi::RedisModuleString,
-                       argc: ::std::os::raw::c_int)
-                       -> i32 {
+extern "C" fn Exec(ctx: *mut ffi::RedisModuleCtx,
+                   argv: *mut *mut ffi::RedisModuleString,
+                   argc: ::std::os::raw::c_int)
+                   -> i32 {
     let (_context, argvector) = create_argument(ctx, argv, argc);
+    let (options, skip) = parse_options(&argvector);
+
     match argvector.len() {
-        2 => {
-            let key_name = create_rm_string(ctx, argvector[1].clone());
-            let key = unsafe {
-                ffi::Export_RedisModule_OpenKey(ctx,
-                                                key_name.rm_string,
-                                                ffi::REDISMODULE_WRITE)
-            };
-            let safe_key = RedisKey { key: key };
-            let key_type = unsafe { ffi::RedisModule_KeyType.unwrap()(key) };
-            if unsafe {
-                ffi::DBType ==
-                ffi::RedisModule_ModuleTypeGetType.unwrap()(safe_key.key) &&
-                key_type != ffi::REDISMODULE_KEYTYPE_EMPTY
-            } {
-                println!("Get the type ok!");
-
-                let db_ptr = unsafe {
-                    ffi::RedisModule_ModuleTypeGetValue.unwrap()(safe_key.key) as *mut db_connection
+        n if n >= 3 + skip => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let query = match query_argument(&raw_argvector[2 + skip]) {
+                Ok(query) => query,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let parameters =
+                match parse_parameters(&raw_argvector[3 + skip..]) {
+                    Ok(parameters) => parameters,
+                    Err(error) => return reply_with_error(ctx, &error),
                 };
+            let action = Action::Exec {
+                query: query,
+                parameters: parameters,
+                options: options,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => {
+            reply_with_error(ctx,
+                             "Wrong number of arguments, it accepts at \
+                              least 3")
+        }
+    }
+}
 
+#[allow(non_snake_case)]
+extern "C" fn Query(ctx: *mut ffi::RedisModuleCtx,
+                    argv: *mut *mut ffi::RedisModuleString,
+                    argc: ::std::os::raw::c_int)
+                    -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+    let (options, skip) = parse_options(&argvector);
 
-                println!("Getting the connection");
+    match argvector.len() {
+        n if n >= 3 + skip => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_READ);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let query = match query_argument(&raw_argvector[2 + skip]) {
+                Ok(query) => query,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let parameters =
+                match parse_parameters(&raw_argvector[3 + skip..]) {
+                    Ok(parameters) => parameters,
+                    Err(error) => return reply_with_error(ctx, &error),
+                };
+            let action = Action::Query {
+                query: query,
+                parameters: parameters,
+                options: options,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
 
-                let _db: Box<db_connection> = unsafe { Box::from_raw(db_ptr) };
+#[allow(non_snake_case)]
+extern "C" fn QueryCursor(ctx: *mut ffi::RedisModuleCtx,
+                          argv: *mut *mut ffi::RedisModuleString,
+                          argc: ::std::os::raw::c_int)
+                          -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+    let (options, skip) = parse_options(&argvector);
 
-                println!("Deleting the key");
-                unsafe {
-                    match ffi::RedisModule_DeleteKey {
-                        Some(f) => {
-                            if safe_key.key.is_null() {
-                                println!("The key is null");
-                            } else {
-                                println!("NOT NULL");
-                            }
-                            println!("Function is available!");
-                            match f(safe_key.key) {
-                                ffi::REDISMODULE_OK => {
-                                    println!("f returned ok");
-                                }
-                                ffi::REDISMODULE_ERR => {
-                                    println!("f returned error");
-                                }
-                                _ => {
-                                    println!("f returned something");
-                                }
-                            }
-                        }
-                        None => println!("The function is not available!"),
-                    }
+    match argvector.len() {
+        n if n >= 3 + skip => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_READ);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let query = match query_argument(&raw_argvector[2 + skip]) {
+                Ok(query) => query,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let parameters =
+                match parse_parameters(&raw_argvector[3 + skip..]) {
+                    Ok(parameters) => parameters,
+                    Err(error) => return reply_with_error(ctx, &error),
                 };
+            let action = Action::QueryCursor {
+                query: query,
+                parameters: parameters,
+                options: options,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
 
-                println!("Send the message");
-                let ok = CString::new("OK").unwrap();
-                unsafe {
-                    ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, ok.as_ptr())
+#[allow(non_snake_case)]
+extern "C" fn Fetch(ctx: *mut ffi::RedisModuleCtx,
+                    argv: *mut *mut ffi::RedisModuleString,
+                    argc: ::std::os::raw::c_int)
+                    -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+
+    match argvector.len() {
+        4 => {
+            let cursor = match argvector[2].parse::<usize>() {
+                Ok(cursor) => cursor,
+                Err(_) => {
+                    return reply_with_error(ctx,
+                                            "ERR - Error, the cursor id must \
+                                             be a number")
                 }
-            } else {
-                match key_type {
-                    ffi::REDISMODULE_KEYTYPE_EMPTY => {
-                        let error = CString::new("ERR - Error the key is \
-                                                  empty")
-                            .unwrap();
-                        unsafe {
-                        ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
-                    }
-                    }
-                    _ => {
-                        let error = CStr::from_bytes_with_nul(ffi::REDISMODULE_ERRORMSG_WRONGTYPE).unwrap();
-                        unsafe {
-                        ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
-                    }
-                    }
+            };
+            let count = match argvector[3].parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => {
+                    return reply_with_error(ctx,
+                                            "ERR - Error, the count must be \
+                                             a positive number")
+                }
+            };
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_READ);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let action = Action::Fetch {
+                cursor: cursor,
+                count: count,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
+
+#[allow(non_snake_case)]
+extern "C" fn CloseCursor(ctx: *mut ffi::RedisModuleCtx,
+                          argv: *mut *mut ffi::RedisModuleString,
+                          argc: ::std::os::raw::c_int)
+                          -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
 
+    match argvector.len() {
+        3 => {
+            let cursor = match argvector[2].parse::<usize>() {
+                Ok(cursor) => cursor,
+                Err(_) => {
+                    return reply_with_error(ctx,
+                                            "ERR - Error, the cursor id must \
+                                             be a number")
                 }
+            };
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_READ);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let action = Action::CloseCursor { cursor: cursor };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
 
-            }
+#[allow(non_snake_case)]
+extern "C" fn ProcessList(ctx: *mut ffi::RedisModuleCtx,
+                          argv: *mut *mut ffi::RedisModuleString,
+                          argc: ::std::os::raw::c_int)
+                          -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
 
+    match argvector.len() {
+        1 => {
+            let processes = PROCESSES.lock().unwrap();
+            unsafe {
+                ffi::RedisModule_ReplyWithArray.unwrap()(ctx,
+                                                         processes.len() as
+                                                         i64);
+            }
+            for (id, process) in processes.iter() {
+                let elapsed = process.started.elapsed();
+                let milliseconds = elapsed.as_secs() * 1000 +
+                                   elapsed.subsec_nanos() as u64 / 1000000;
+                let key = &process.key;
+                let sql = &process.sql;
+                unsafe {
+                    ffi::RedisModule_ReplyWithArray.unwrap()(ctx, 5);
+                    ffi::RedisModule_ReplyWithLongLong.unwrap()(ctx,
+                                                                *id as i64);
+                    ffi::RedisModule_ReplyWithStringBuffer
+                        .unwrap()(ctx, key.as_ptr() as *const i8, key.len());
+                    ffi::RedisModule_ReplyWithLongLong
+                        .unwrap()(ctx, process.client_id as i64);
+                    ffi::RedisModule_ReplyWithStringBuffer
+                        .unwrap()(ctx, sql.as_ptr() as *const i8, sql.len());
+                    ffi::RedisModule_ReplyWithLongLong
+                        .unwrap()(ctx, milliseconds as i64);
+                }
+            }
+            ffi::REDISMODULE_OK
         }
         _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
     }
 }
 
+// Interrupt a running statement, its client gets an error back.
 #[allow(non_snake_case)]
-extern "C" fn Exec(ctx: *mut ffi::RedisModuleCtx,
+extern "C" fn Kill(ctx: *mut ffi::RedisModuleCtx,
                    argv: *mut *mut ffi::RedisModuleString,
                    argc: ::std::os::raw::c_int)
                    -> i32 {
     let (_context, argvector) = create_argument(ctx, argv, argc);
 
     match argvector.len() {
-        3 => {
-            let key_name = create_rm_string(ctx, argvector[1].clone());
-            let key = unsafe {
-                ffi::Export_RedisModule_OpenKey(ctx,
-                                                key_name.rm_string,
-                                                ffi::REDISMODULE_WRITE)
-            };
-            let safe_key = RedisKey { key: key };
-            let key_type =
-                unsafe { ffi::RedisModule_KeyType.unwrap()(safe_key.key) };
-            if unsafe {
-                ffi::DBType ==
-                ffi::RedisModule_ModuleTypeGetType.unwrap()(safe_key.key)
-            } {
-                println!("Get the type ok!");
-
-                let db_ptr = unsafe {
-                    ffi::RedisModule_ModuleTypeGetValue.unwrap()(safe_key.key) as *mut db_connection
-                };
+        2 => {
+            let id = match argvector[1].parse::<usize>() {
+                Ok(id) => id,
+                Err(_) => {
+                    return reply_with_error(ctx,
+                                            "ERR - Error, the query id must \
+                                             be a number")
+                }
+            };
+            match PROCESSES.lock().unwrap().get_mut(&id) {
+                Some(process) => {
+                    process.killed.store(true, Ordering::SeqCst);
+                    reply_with_ok(ctx)
+                }
+                None => {
+                    reply_with_error(ctx, "ERR - Error, no query with this id")
+                }
+            }
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
+
+// BEGIN, COMMIT and ROLLBACK share everything but the action.
+fn transaction_command(ctx: *mut ffi::RedisModuleCtx,
+                       argv: *mut *mut ffi::RedisModuleString,
+                       argc: ::std::os::raw::c_int,
+                       action: Action)
+                       -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
 
-                let db: Box<db_connection> = unsafe { Box::from_raw(db_ptr) };
+    match argvector.len() {
+        2 => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            if let Action::Begin = action {
+                let client_id =
+                    unsafe { ffi::RedisModule_GetClientId.unwrap()(ctx) };
+                watch_disconnection(client_id, &db.queue);
+            }
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
 
+#[allow(non_snake_case)]
+extern "C" fn Begin(ctx: *mut ffi::RedisModuleCtx,
+                    argv: *mut *mut ffi::RedisModuleString,
+                    argc: ::std::os::raw::c_int)
+                    -> i32 {
+    transaction_command(ctx, argv, argc, Action::Begin)
+}
 
-                match create_statement(&db.connection, argvector[2].clone()) {
-                    Ok(stmt) => {
+#[allow(non_snake_case)]
+extern "C" fn Commit(ctx: *mut ffi::RedisModuleCtx,
+                     argv: *mut *mut ffi::RedisModuleString,
+                     argc: ::std::os::raw::c_int)
+                     -> i32 {
+    transaction_command(ctx, argv, argc, Action::Commit)
+}
 
+#[allow(non_snake_case)]
+extern "C" fn Rollback(ctx: *mut ffi::RedisModuleCtx,
+                       argv: *mut *mut ffi::RedisModuleString,
+                       argc: ::std::os::raw::c_int)
+                       -> i32 {
+    transaction_command(ctx, argv, argc, Action::Rollback)
+}
 
-                        // mem::forget(db);
+#[allow(non_snake_case)]
+extern "C" fn ExecScript(ctx: *mut ffi::RedisModuleCtx,
+                         argv: *mut *mut ffi::RedisModuleString,
+                         argc: ::std::os::raw::c_int)
+                         -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
 
-                        Box::into_raw(db);
+    match argvector.len() {
+        3 => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let script = match query_argument(&raw_argvector[2]) {
+                Ok(script) => script,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let action = Action::ExecScript { script: script };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
 
-                        match execute_statement(stmt) {
-                            Ok(cursor) => {
-                                match cursor {
-                                    Cursor::OKCursor => {
-                                        let ok = CString::new("OK").unwrap();
-                                        unsafe {
-                                            ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, ok.as_ptr())
-                                        }
-                                    }
-                                    Cursor::DONECursor => {
-                                        let done = CString::new("DONE")
-                                            .unwrap();
-                                        unsafe {
-                                            ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, done.as_ptr())
-                                        }
-                                    }
-                                    Cursor::RowsCursor { .. } => {
-                                        let result =
-                                            cursor.collect::<Vec<Vec<Entity>>>();
-                                        unsafe {
-                                            ffi::RedisModule_ReplyWithArray.unwrap()(ctx, result.len() as i64);
-                                        }
-                                        for row in result {
-                                            unsafe {
-                                                ffi::RedisModule_ReplyWithArray.unwrap()(ctx, row.len() as i64);
-                                            }
-                                            for entity in row {
-                                                entity.reply(ctx);
-                                            }
-                                        }
-
-                                        ffi::REDISMODULE_OK
-                                    }
-                                }
-                            }
-                            Err(_) => {
-                                let error = CString::new("ERR - Error, the \
-                                                          statement to \
-                                                          executed gave \
-                                                          some problem")
-                                    .unwrap();
-                                unsafe {
-                            ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
-                        }
-                            }
-                        }
-                    }
-                    Err(_) => {
+// Only found in the replication stream and in the AOF, see take_effects.
+#[allow(non_snake_case)]
+extern "C" fn ApplyEffects(ctx: *mut ffi::RedisModuleCtx,
+                           argv: *mut *mut ffi::RedisModuleString,
+                           argc: ::std::os::raw::c_int)
+                           -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
 
+    match argvector.len() {
+        3 => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let script = match query_argument(&raw_argvector[2]) {
+                Ok(script) => script,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let action = Action::ApplyEffects { script: script };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
 
-                        Box::into_raw(db);
+#[allow(non_snake_case)]
+extern "C" fn ExecBatch(ctx: *mut ffi::RedisModuleCtx,
+                        argv: *mut *mut ffi::RedisModuleString,
+                        argc: ::std::os::raw::c_int)
+                        -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
 
-                        let error = CString::new("ERR - Error, was \
-                                                  impossible to create the \
-                                                  statement")
-                            .unwrap();
-                        unsafe {
-                            ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
-                        }
-                    }
+    match argvector.len() {
+        n if n >= 4 => {
+            let columns = match argvector[3].parse::<usize>() {
+                Ok(columns) if columns > 0 && (n - 4) % columns == 0 => {
+                    columns
                 }
-            } else {
-                match key_type {
-                    ffi::REDISMODULE_KEYTYPE_EMPTY => {
-                        let error = CString::new("ERR - Error the key is \
-                                                  empty")
-                            .unwrap();
-                        unsafe {
-                        ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
-                    }
-                    }
-                    _ => {
-                        let error = CStr::from_bytes_with_nul(ffi::REDISMODULE_ERRORMSG_WRONGTYPE).unwrap();
-                        unsafe {
-                        ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
-                    }
-                    }
+                _ => {
+                    return reply_with_error(ctx,
+                                            "ERR - Error, the number of \
+                                             columns must be a positive \
+                                             number that divides the number \
+                                             of values")
                 }
+            };
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let query = match query_argument(&raw_argvector[2]) {
+                Ok(query) => query,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let parameters = match parse_parameters(&raw_argvector[4..]) {
+                Ok(parameters) => parameters,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let action = Action::ExecBatch {
+                query: query,
+                columns: columns,
+                parameters: parameters,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
+
+// REDISQL.IMPORT_CSV key table source [HEADER] [DELIMITER c] [FILE]
+//
+// With FILE the source is the path of a file on the server, in the
+// FILE_DIRECTORY, it is read by the worker and the replicas get the content
+// of the file, not its path.
+#[allow(non_snake_case)]
+extern "C" fn ImportCsv(ctx: *mut ffi::RedisModuleCtx,
+                        argv: *mut *mut ffi::RedisModuleString,
+                        argc: ::std::os::raw::c_int)
+                        -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+    if argvector.len() < 4 {
+        return unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) };
+    }
+    let mut raw_argvector = parse_raw_args(argv, argc);
+
+    let mut header = false;
+    let mut delimiter = b',';
+    let mut from_file = false;
+    let mut i = 4;
+    while i < raw_argvector.len() {
+        match argvector[i].to_uppercase().as_str() {
+            "HEADER" => header = true,
+            "FILE" => from_file = true,
+            "DELIMITER" if i + 1 < raw_argvector.len() &&
+                           raw_argvector[i + 1].len() == 1 => {
+                delimiter = raw_argvector[i + 1][0];
+                i += 1;
+            }
+            _ => {
+                return reply_with_error(ctx,
+                                        "ERR - Error, the options are \
+                                         HEADER, DELIMITER followed by a \
+                                         single character and FILE")
+            }
+        }
+        i += 1;
+    }
+    if delimiter == b'"' || delimiter == b'\n' || delimiter == b'\r' {
+        return reply_with_error(ctx, "ERR - Error, invalid delimiter");
+    }
+
+    let source = if from_file {
+        match confined_path(&argvector[3]) {
+            Ok(path) => CsvSource::File(path),
+            Err(error) => return reply_with_error(ctx, &error),
+        }
+    } else {
+        CsvSource::Data(raw_argvector.swap_remove(3))
+    };
+
+    let safe_key = open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+    let db = match get_db_connection(ctx, &safe_key) {
+        Ok(db_ptr) => unsafe { &*db_ptr },
+        Err(reply) => return reply,
+    };
+    let action = Action::ImportCsv {
+        table: argvector[2].clone(),
+        source: source,
+        header: header,
+        delimiter: delimiter,
+    };
+    send_to_worker(ctx, argv, argc, db, action)
+}
+
+#[allow(non_snake_case)]
+extern "C" fn CreateStatement(ctx: *mut ffi::RedisModuleCtx,
+                              argv: *mut *mut ffi::RedisModuleString,
+                              argc: ::std::os::raw::c_int)
+                              -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+
+    match argvector.len() {
+        4 => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let query = match query_argument(&raw_argvector[3]) {
+                Ok(query) => query,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let action = Action::CreateStatement {
+                name: argvector[2].clone(),
+                query: query,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
 
-            }
+#[allow(non_snake_case)]
+extern "C" fn UpdateStatement(ctx: *mut ffi::RedisModuleCtx,
+                              argv: *mut *mut ffi::RedisModuleString,
+                              argc: ::std::os::raw::c_int)
+                              -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+
+    match argvector.len() {
+        4 => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let query = match query_argument(&raw_argvector[3]) {
+                Ok(query) => query,
+                Err(error) => return reply_with_error(ctx, &error),
+            };
+            let action = Action::UpdateStatement {
+                name: argvector[2].clone(),
+                query: query,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
         }
-        _ => {
-            let error = CString::new("Wrong number of arguments, it accepts \
-                                      3")
-                .unwrap();
-            unsafe {
-                ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
-            }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
+}
+
+#[allow(non_snake_case)]
+extern "C" fn DeleteStatement(ctx: *mut ffi::RedisModuleCtx,
+                              argv: *mut *mut ffi::RedisModuleString,
+                              argc: ::std::os::raw::c_int)
+                              -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+
+    match argvector.len() {
+        3 => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let action =
+                Action::DeleteStatement { name: argvector[2].clone() };
+            send_to_worker(ctx, argv, argc, db, action)
         }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
     }
+}
+
+#[allow(non_snake_case)]
+extern "C" fn ExecStatement(ctx: *mut ffi::RedisModuleCtx,
+                            argv: *mut *mut ffi::RedisModuleString,
+                            argc: ::std::os::raw::c_int)
+                            -> i32 {
+    let (_context, argvector) = create_argument(ctx, argv, argc);
+    let (options, skip) = parse_options(&argvector);
 
+    match argvector.len() {
+        n if n >= 3 + skip => {
+            let safe_key =
+                open_key(ctx, argv, ffi::REDISMODULE_WRITE);
+            let db = match get_db_connection(ctx, &safe_key) {
+                Ok(db_ptr) => unsafe { &*db_ptr },
+                Err(reply) => return reply,
+            };
+            let raw_argvector = parse_raw_args(argv, argc);
+            let parameters =
+                match parse_parameters(&raw_argvector[3 + skip..]) {
+                    Ok(parameters) => parameters,
+                    Err(error) => return reply_with_error(ctx, &error),
+                };
+            let action = Action::ExecStatement {
+                name: argvector[2 + skip].clone(),
+                parameters: parameters,
+                options: options,
+            };
+            send_to_worker(ctx, argv, argc, db, action)
+        }
+        _ => unsafe { ffi::RedisModule_WrongArity.unwrap()(ctx) },
+    }
 }
 
 #[allow(non_snake_case)]
@@ -545,63 +4399,69 @@ extern "C" fn CreateDB(ctx: *mut ffi::RedisModuleCtx,
     let (_context, argvector) = create_argument(ctx, argv, argc);
 
     match argvector.len() {
-        2 => {
-            let key_name = create_rm_string(ctx, argvector[1].clone());
-            let key = unsafe {
-                ffi::Export_RedisModule_OpenKey(ctx,
-                                                key_name.rm_string,
-                                                ffi::REDISMODULE_WRITE)
-            };
-            let safe_key = RedisKey { key: key };
+        2 | 3 => {
+            let safe_key = open_key(ctx, argv, ffi::REDISMODULE_WRITE);
             match unsafe { ffi::RedisModule_KeyType.unwrap()(safe_key.key) } {
 
                 ffi::REDISMODULE_KEYTYPE_EMPTY => {
 
                     println!("Open the empty key!");
 
-                    match open_connection(String::from(":memory:")) {
+                    // A file that cannot be opened here, when the command
+                    // comes from the master or the AOF, leaves the database
+                    // in memory: the writes still come.
+                    let file = match argvector.get(2).map(|path| {
+                        confined_path(path)
+                    }) {
+                        Some(Ok(file)) => Some(file),
+                        Some(Err(ref error)) if from_master_or_aof(ctx) => {
+                            log_warning(error);
+                            None
+                        }
+                        Some(Err(error)) => {
+                            return reply_with_error(ctx, &error)
+                        }
+                        None => None,
+                    };
+                    let path = file.as_ref().map(|file| relative_path(file));
+                    let open_path = file.unwrap_or(String::from(":memory:"));
+                    match open_connection(open_path) {
                         Ok(rc) => {
                             println!("Open the database");
-                            let ptr = Box::into_raw(Box::new(rc));
+                            let database = create_database(rc);
+                            let mut replication =
+                                parse_raw_args(argv, argc);
+                            replication.truncate(2);
+                            if let Some(ref path) = path {
+                                replication.push(path.clone().into_bytes());
+                            }
+                            let db = create_db_connection(database, path);
+                            let ptr = Box::into_raw(Box::new(db));
                             let type_set = unsafe {
                                 ffi::RedisModule_ModuleTypeSetValue.unwrap()(safe_key.key, ffi::DBType, ptr as *mut std::os::raw::c_void)
                             };
                             match type_set {
                                 ffi::REDISMODULE_OK => {
+                                    replicate(ctx, &replication);
                                     let ok = CString::new("OK").unwrap();
                                     unsafe {
                                         ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, ok.as_ptr())
                                     }
                                 }
-                                ffi::REDISMODULE_ERR => {
-                                    let err = CString::new("ERR - Error in \
-                                                            saving the \
-                                                            database inside \
-                                                            Redis")
-                                        .unwrap();
-
-                                    unsafe {
-                                        ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, err.as_ptr())
-                                    }
-                                }
                                 _ => {
-                                    let err = CString::new("ERR - Error \
-                                                            unknow")
-                                        .unwrap();
-
+                                    // Redis did not take the ownership of
+                                    // the database.
                                     unsafe {
-                                        ffi::RedisModule_ReplyWithSimpleString.unwrap()(ctx, err.as_ptr())
+                                        drop(Box::from_raw(ptr));
                                     }
+                                    reply_with_error(ctx,
+                                                     "ERR - Error in saving \
+                                                      the database inside \
+                                                      Redis")
                                 }
                             }
                         }
-                        Err(_) => {
-                            let error = CString::new("Err - Error \
-                                                      opening the in \
-                                                      memory databade")
-                                .unwrap();
-                            unsafe { ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr()) }
-                        }
+                        Err(e) => reply_with_error(ctx, &error_reply(e)),
                     }
                 }
 
@@ -618,7 +4478,7 @@ extern "C" fn CreateDB(ctx: *mut ffi::RedisModuleCtx,
         _ => {
             println!("Wrong number of arguments");
             let error = CString::new("Wrong number of arguments, it accepts \
-                                      2")
+                                      2 or 3")
                 .unwrap();
             unsafe {
                 ffi::RedisModule_ReplyWithError.unwrap()(ctx, error.as_ptr())
@@ -639,23 +4499,413 @@ fn parse_args(argv: *mut *mut ffi::RedisModuleString,
     Ok(args)
 }
 
-pub fn string_ptr_len(str: *mut ffi::RedisModuleString) -> String {
+// Arguments as the client sent them, byte by byte, used for the values
+// bound to the statements.
+fn parse_raw_args(argv: *mut *mut ffi::RedisModuleString,
+                  argc: i32)
+                  -> Vec<Vec<u8>> {
+    let mut args: Vec<Vec<u8>> = Vec::with_capacity(argc as usize);
+    for i in 0..argc {
+        let redis_str = unsafe { *argv.offset(i as isize) };
+        args.push(string_ptr_len_raw(redis_str));
+    }
+    args
+}
+
+fn string_ptr_len_raw(str: *mut ffi::RedisModuleString) -> Vec<u8> {
+    let mut len: usize = 0;
     unsafe {
-        CStr::from_ptr(ffi::RedisModule_StringPtrLen.unwrap()(str, std::ptr::null_mut()))
-            .to_string_lossy()
-            .into_owned()
+        let ptr = ffi::RedisModule_StringPtrLen.unwrap()(str, &mut len);
+        std::slice::from_raw_parts(ptr as *const u8, len).to_vec()
     }
 }
 
-unsafe extern "C" fn free_db(_: *mut ::std::os::raw::c_void) {
+pub fn string_ptr_len(str: *mut ffi::RedisModuleString) -> String {
+    String::from_utf8_lossy(&string_ptr_len_raw(str)).into_owned()
+}
+
+// Called by Redis whenever the value goes away: DEL, UNLINK, FLUSHDB,
+// overwriting the key or its expiration. The statements and the connection
+// are closed once the workers have replied to the clients still waiting for
+// the database.
+unsafe extern "C" fn free_db(value: *mut ::std::os::raw::c_void) {
     println!("Call free");
+    let db = Box::from_raw(value as *mut db_connection);
+    // The writes still queued are not replicated anymore.
+    *db.queue.key.lock().unwrap() = None;
+    drop(db);
+}
+
+// The path of the database file, if any, the database itself and the named
+// statements.
+const RDB_ENCODING_VERSION: i32 = 1;
+const RDB_CHUNK_SIZE: usize = 64 * 1024;
+
+// The database is copied, through the SQLite backup API, into a temporary
+// file, the file is then stored in the RDB as a sequence of chunks. Nothing
+// is written in the RDB if the copy fails.
+fn write_database_to_rdb(rdb: *mut ffi::RedisModuleIO,
+                         conn: &RawConnection)
+                         -> Result<(), SQLite3Error> {
+    let path = temp_db_path();
+    let result = open_connection(path.clone()).and_then(|file_db| {
+        backup_connection(conn, &file_db)
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&path);
+        return Err(e);
+    }
+
+    let mut chunks: Vec<Vec<u8>> = Vec::new();
+    match fs::File::open(&path) {
+        Ok(mut file) => {
+            loop {
+                let mut buffer = vec![0u8; RDB_CHUNK_SIZE];
+                match file.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buffer.truncate(n);
+                        chunks.push(buffer);
+                    }
+                    Err(e) => {
+                        log_warning(&format!("Error reading the backup \
+                                              file: {}",
+                                             e));
+                        let _ = fs::remove_file(&path);
+                        return Err(SQLite3Error::new(ffi::SQLITE_IOERR,
+                                                     e.to_string()));
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            log_warning(&format!("Error opening the backup file: {}", e));
+            let _ = fs::remove_file(&path);
+            return Err(SQLite3Error::new(ffi::SQLITE_IOERR, e.to_string()));
+        }
+    }
+    let _ = fs::remove_file(&path);
+
+    unsafe {
+        ffi::RedisModule_SaveUnsigned.unwrap()(rdb, 1);
+        ffi::RedisModule_SaveUnsigned.unwrap()(rdb, chunks.len() as u64);
+        for chunk in chunks {
+            ffi::RedisModule_SaveStringBuffer.unwrap()(rdb,
+                                                       chunk.as_ptr() as *const i8,
+                                                       chunk.len());
+        }
+    }
+    Ok(())
+}
+
+fn read_database_from_rdb(rdb: *mut ffi::RedisModuleIO)
+                          -> Result<RawConnection, SQLite3Error> {
+    if unsafe { ffi::RedisModule_LoadUnsigned.unwrap()(rdb) } == 0 {
+        return Err(SQLite3Error::new(ffi::SQLITE_ERROR,
+                                     String::from("the database could not \
+                                                   be saved in the RDB")));
+    }
+    let path = temp_db_path();
+    let result = fs::File::create(&path).and_then(|mut file| {
+        let n_chunks = unsafe { ffi::RedisModule_LoadUnsigned.unwrap()(rdb) };
+        for _ in 0..n_chunks {
+            let mut len: usize = 0;
+            unsafe {
+                let buffer =
+                    ffi::RedisModule_LoadStringBuffer.unwrap()(rdb, &mut len);
+                let chunk = std::slice::from_raw_parts(buffer as *const u8,
+                                                       len);
+                let written = file.write_all(chunk);
+                ffi::RedisModule_Free.unwrap()(buffer as *mut std::os::raw::c_void);
+                written?;
+            }
+        }
+        file.flush()
+    });
+    if let Err(e) = result {
+        log_warning(&format!("Error writing the backup file: {}", e));
+        let _ = fs::remove_file(&path);
+        return Err(SQLite3Error::new(ffi::SQLITE_IOERR, e.to_string()));
+    }
+
+    let restored = open_connection(path.clone()).and_then(|file_db| {
+        open_connection(String::from(":memory:")).and_then(|memory_db| {
+            backup_connection(&file_db, &memory_db).map(|_| memory_db)
+        })
+    });
+    let _ = fs::remove_file(&path);
+    restored
+}
+
+fn save_rdb_string(rdb: *mut ffi::RedisModuleIO, s: &str) {
+    unsafe {
+        ffi::RedisModule_SaveStringBuffer.unwrap()(rdb,
+                                                   s.as_ptr() as *const i8,
+                                                   s.len());
+    }
+}
+
+fn load_rdb_string(rdb: *mut ffi::RedisModuleIO) -> String {
+    let mut len: usize = 0;
+    unsafe {
+        let buffer = ffi::RedisModule_LoadStringBuffer.unwrap()(rdb, &mut len);
+        let s = String::from_utf8_lossy(std::slice::from_raw_parts(buffer as
+                                                                   *const u8,
+                                                                   len))
+            .into_owned();
+        ffi::RedisModule_Free.unwrap()(buffer as *mut std::os::raw::c_void);
+        s
+    }
+}
+
+// A database that cannot be saved must not end up empty in the RDB. The
+// forked child gives up, the save fails and the previous RDB is kept. The
+// main thread cannot fail the save, it leaves a mark in the RDB instead and
+// loading the RDB fails.
+fn save_failed(rdb: *mut ffi::RedisModuleIO, error: &str) {
+    log_warning(&format!("Error saving the database in the RDB: {}", error));
+    if FORKED.load(Ordering::SeqCst) {
+        unsafe {
+            libc::_exit(1);
+        }
+    }
+    unsafe {
+        ffi::RedisModule_SaveUnsigned.unwrap()(rdb, 0);
+    }
+}
+
+unsafe extern "C" fn rdb_save(rdb: *mut ffi::RedisModuleIO,
+                              value: *mut std::os::raw::c_void) {
+    let db = &*(value as *mut db_connection);
+    match db.path {
+        Some(ref path) => {
+            ffi::RedisModule_SaveUnsigned.unwrap()(rdb, 1);
+            save_rdb_string(rdb, path);
+        }
+        None => ffi::RedisModule_SaveUnsigned.unwrap()(rdb, 0),
+    }
+    let database = match lock_to_save(db) {
+        Some(database) => database,
+        None => {
+            save_failed(rdb, "the database is locked");
+            ffi::RedisModule_SaveUnsigned.unwrap()(rdb, 0);
+            return;
+        }
+    };
+    // The content of a database file is saved as well, the RDB may be
+    // loaded where the file does not exist.
+    if let Err(e) = write_database_to_rdb(rdb,
+                                          database.committed_connection()) {
+        save_failed(rdb, &e.to_string());
+    }
+
+    ffi::RedisModule_SaveUnsigned.unwrap()(rdb,
+                                           database.statements.len() as u64);
+    for (name, &(ref sql, _)) in &database.statements {
+        save_rdb_string(rdb, name);
+        save_rdb_string(rdb, sql);
+    }
+}
+
+fn load_statements_from_rdb(rdb: *mut ffi::RedisModuleIO,
+                            db: &mut Database) {
+    let n_statements = unsafe { ffi::RedisModule_LoadUnsigned.unwrap()(rdb) };
+    for _ in 0..n_statements {
+        let name = load_rdb_string(rdb);
+        let sql = load_rdb_string(rdb);
+        match create_statement(&db.connection, sql.clone()) {
+            Ok(stmt) => {
+                db.statements.insert(name, (sql, stmt));
+            }
+            Err(e) => {
+                log_warning(&format!("Error loading the statement {}: \
+                                      {:?}",
+                                     name,
+                                     e));
+            }
+        }
+    }
+}
+
+// The file is the most recent copy of the database, the one in the RDB is
+// used only to fill the file when it is empty, as when it does not exist
+// anymore or the RDB comes from another server.
+fn open_database_file(rdb: *mut ffi::RedisModuleIO,
+                      path: &str)
+                      -> Result<RawConnection, SQLite3Error> {
+    let saved = read_database_from_rdb(rdb);
+    let file_db = open_connection(String::from(path))?;
+    let count = create_statement(&file_db,
+                                 String::from("SELECT count(*) FROM \
+                                               sqlite_master;"))?;
+    let empty = match unsafe { ffi::sqlite3_step(count.stmt) } {
+        ffi::SQLITE_ROW => {
+            unsafe { ffi::sqlite3_column_int64(count.stmt, 0) == 0 }
+        }
+        _ => return Err(SQLite3Error::from_statement(&count)),
+    };
+    drop(count);
+    match saved {
+        Ok(ref saved) if empty => backup_connection(saved, &file_db)?,
+        Ok(_) => {}
+        Err(e) if empty => return Err(e),
+        Err(e) => {
+            log_warning(&format!("Error loading the database from the \
+                                  RDB, keeping the file {}: {:?}",
+                                 path,
+                                 e))
+        }
+    }
+    Ok(file_db)
+}
+
+unsafe extern "C" fn rdb_load(rdb: *mut ffi::RedisModuleIO,
+                              encoding_version: i32)
+                              -> *mut std::os::raw::c_void {
+    match encoding_version {
+        RDB_ENCODING_VERSION => {
+            let path = match ffi::RedisModule_LoadUnsigned.unwrap()(rdb) {
+                0 => None,
+                _ => Some(load_rdb_string(rdb)),
+            };
+            // A file that cannot be opened here leaves the database in
+            // memory, the RDB has its content anyway.
+            let (path, connection) = match path.map(|path| {
+                confined_path(&path)
+            }) {
+                Some(Ok(file)) => {
+                    let connection = open_database_file(rdb, &file);
+                    (Some(relative_path(&file)), connection)
+                }
+                Some(Err(error)) => {
+                    log_warning(&error);
+                    (None, read_database_from_rdb(rdb))
+                }
+                None => (None, read_database_from_rdb(rdb)),
+            };
+            match connection {
+                Ok(rc) => {
+                    let mut database = create_database(rc);
+                    load_statements_from_rdb(rdb, &mut database);
+                    let db = create_db_connection(database, path);
+                    Box::into_raw(Box::new(db)) as *mut std::os::raw::c_void
+                }
+                Err(e) => {
+                    log_warning(&format!("Error loading the database from \
+                                          the RDB: {:?}",
+                                         e));
+                    ptr::null_mut()
+                }
+            }
+        }
+        x => {
+            log_warning(&format!("Unknow encoding version of the RDB: {}", x));
+            ptr::null_mut()
+        }
+    }
+}
+
+// Like save_failed, the rewrite happens in a forked child that gives up
+// rather than leaving the database incomplete in the AOF.
+fn rewrite_failed(error: &str) {
+    log_warning(&format!("Error rewriting the database in the AOF: {}",
+                         error));
+    if FORKED.load(Ordering::SeqCst) {
+        unsafe {
+            libc::_exit(1);
+        }
+    }
+}
+
+unsafe extern "C" fn aof_rewrite(aof: *mut ffi::RedisModuleIO,
+                                 key: *mut ffi::RedisModuleString,
+                                 value: *mut std::os::raw::c_void) {
+    let db = &*(value as *mut db_connection);
+    let database = match lock_to_save(db) {
+        Some(database) => database,
+        None => return rewrite_failed("the database is locked"),
+    };
+
+    let create_db = CString::new("REDISQL.CREATE_DB").unwrap();
+    let exec = CString::new("REDISQL.EXEC").unwrap();
+    let key_fmt = CString::new("s").unwrap();
+    let key_path_fmt = CString::new("sb").unwrap();
+    let exec_fmt = CString::new("sb").unwrap();
+
+    match db.path {
+        // Unlike the RDB, that saves the content of the file as well, the
+        // AOF only reopens the file: the data are already on disk, and
+        // replaying them would apply them twice. An AOF without the RDB
+        // preamble, aof-use-rdb-preamble no, cannot restore a database file
+        // that has been lost.
+        Some(ref path) => {
+            ffi::RedisModule_EmitAOF.unwrap()(aof,
+                                              create_db.as_ptr(),
+                                              key_path_fmt.as_ptr(),
+                                              key,
+                                              path.as_ptr() as *const i8,
+                                              path.len());
+        }
+        None => {
+            ffi::RedisModule_EmitAOF.unwrap()(aof,
+                                              create_db.as_ptr(),
+                                              key_fmt.as_ptr(),
+                                              key);
+            let committed = database.committed_connection();
+            let result = dump_database(committed, |sql| {
+                ffi::RedisModule_EmitAOF.unwrap()(aof,
+                                                  exec.as_ptr(),
+                                                  exec_fmt.as_ptr(),
+                                                  key,
+                                                  sql.as_ptr() as *const i8,
+                                                  sql.len());
+            });
+            if let Err(e) = result {
+                return rewrite_failed(&e.to_string());
+            }
+        }
+    }
+
+    let create_statement = CString::new("REDISQL.CREATE_STATEMENT").unwrap();
+    let statement_fmt = CString::new("sbb").unwrap();
+    for (name, &(ref sql, _)) in &database.statements {
+        ffi::RedisModule_EmitAOF.unwrap()(aof,
+                                          create_statement.as_ptr(),
+                                          statement_fmt.as_ptr(),
+                                          key,
+                                          name.as_ptr() as *const i8,
+                                          name.len(),
+                                          sql.as_ptr() as *const i8,
+                                          sql.len());
+    }
+}
+
+fn create_command(ctx: *mut ffi::RedisModuleCtx,
+                  name: &str,
+                  command: ffi::RedisModuleCmdFunc,
+                  flags: &str,
+                  first_key: i32,
+                  last_key: i32,
+                  key_step: i32)
+                  -> i32 {
+    let command_c_name = CString::new(name).unwrap();
+    let flag_c_name = CString::new(flags).unwrap();
+    unsafe {
+        ffi::RedisModule_CreateCommand.unwrap()(ctx,
+                                                command_c_name.as_ptr(),
+                                                command,
+                                                flag_c_name.as_ptr(),
+                                                first_key,
+                                                last_key,
+                                                key_step)
+    }
 }
 
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn RedisModule_OnLoad(ctx: *mut ffi::RedisModuleCtx,
-                                     _argv: *mut *mut ffi::RedisModuleString,
-                                     _argc: i32)
+                                     argv: *mut *mut ffi::RedisModuleString,
+                                     argc: i32)
                                      -> i32 {
 
     println!("Starting!");
@@ -666,9 +4916,9 @@ pub extern "C" fn RedisModule_OnLoad(ctx: *mut ffi::RedisModuleCtx,
 
     let mut types = ffi::RedisModuleTypeMethods {
         version: 1,
-        rdb_load: None,
-        rdb_save: None,
-        aof_rewrite: None,
+        rdb_load: Some(rdb_load),
+        rdb_save: Some(rdb_save),
+        aof_rewrite: Some(aof_rewrite),
         mem_usage: None,
         digest: None,
         free: Some(free_db),
@@ -686,13 +4936,103 @@ pub extern "C" fn RedisModule_OnLoad(ctx: *mut ffi::RedisModuleCtx,
         return ffi::REDISMODULE_ERR;
     }
 
+    let mut options = parse_args(argv, argc).unwrap().into_iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "BIGINT_AS_STRING" => {
+                BIGINT_AS_STRING.store(true, Ordering::Relaxed)
+            }
+            "REPLICATE_EFFECTS" => {
+                REPLICATE_EFFECTS.store(true, Ordering::Relaxed)
+            }
+            "QUERY_TIMEOUT" => {
+                match options.next().and_then(|ms| ms.parse::<usize>().ok()) {
+                    Some(ms) => QUERY_TIMEOUT.store(ms, Ordering::Relaxed),
+                    None => {
+                        log_warning("QUERY_TIMEOUT needs the milliseconds");
+                        return ffi::REDISMODULE_ERR;
+                    }
+                }
+            }
+            "FILE_DIRECTORY" => {
+                match options.next()
+                    .and_then(|directory| fs::canonicalize(directory).ok()) {
+                    Some(directory) => {
+                        *FILE_DIRECTORY.lock().unwrap() = Some(directory)
+                    }
+                    None => {
+                        log_warning("FILE_DIRECTORY needs an existing \
+                                     directory");
+                        return ffi::REDISMODULE_ERR;
+                    }
+                }
+            }
+            "WORKER_THREADS" => {
+                match options.next()
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .and_then(|n| if n > 0 { Some(n) } else { None }) {
+                    Some(n) => WORKER_THREADS.store(n, Ordering::Relaxed),
+                    None => {
+                        log_warning("WORKER_THREADS needs a number of \
+                                     threads");
+                        return ffi::REDISMODULE_ERR;
+                    }
+                }
+            }
+            _ => {
+                log_warning(&format!("Unknow module option: {}", option));
+                return ffi::REDISMODULE_ERR;
+            }
+        }
+    }
+
+    start_workers(WORKER_THREADS.load(Ordering::Relaxed));
+    unsafe {
+        libc::pthread_atfork(Some(before_fork),
+                             Some(after_fork_in_parent),
+                             Some(after_fork_in_child));
+    }
+
+    let client_change = ffi::RedisModuleEvent {
+        id: ffi::REDISMODULE_EVENT_CLIENT_CHANGE as u64,
+        dataver: 1,
+    };
+    if unsafe {
+        ffi::RedisModule_SubscribeToServerEvent.unwrap()(ctx,
+                                                         client_change,
+                                                         Some(client_changed))
+    } == ffi::REDISMODULE_ERR {
+        log_warning("Error subscribing to the client change events");
+        return ffi::REDISMODULE_ERR;
+    }
+    let swapdb = ffi::RedisModuleEvent {
+        id: ffi::REDISMODULE_EVENT_SWAPDB as u64,
+        dataver: 1,
+    };
+    let subscribed = unsafe {
+        ffi::RedisModule_SubscribeToServerEvent
+            .unwrap()(ctx, swapdb, Some(databases_swapped))
+    };
+    if subscribed == ffi::REDISMODULE_ERR {
+        log_warning("Error subscribing to the SWAPDB events");
+        return ffi::REDISMODULE_ERR;
+    }
+    let subscribed = unsafe {
+        ffi::RedisModule_SubscribeToKeyspaceEvents
+            .unwrap()(ctx, ffi::REDISMODULE_NOTIFY_GENERIC, Some(key_moved))
+    };
+    if subscribed == ffi::REDISMODULE_ERR {
+        log_warning("Error subscribing to the keyspace events");
+        return ffi::REDISMODULE_ERR;
+    }
+
     println!("About to register the type!");
 
     unsafe {
         ffi::DBType =
             ffi::RedisModule_CreateDataType.unwrap()(ctx,
                                                      ptr_data_type_name,
-                                                     1,
+                                                     RDB_ENCODING_VERSION,
                                                      &mut types);
     }
 
@@ -703,72 +5043,451 @@ pub extern "C" fn RedisModule_OnLoad(ctx: *mut ffi::RedisModuleCtx,
         return ffi::REDISMODULE_ERR;
     }
 
-    let create_db: ffi::RedisModuleCmdFunc = Some(CreateDB);
+    // Every command, but the ones about the running queries, works on the
+    // single database key given as first argument, declaring it lets Redis
+    // Cluster route the command.
+    let commands: Vec<(&str, ffi::RedisModuleCmdFunc, &str, i32)> =
+        vec![("REDISQL.CREATE_DB", Some(CreateDB), "write", 1),
+             ("REDISQL.Delete_DB", Some(DeleteDB), "write", 1),
+             ("REDISQL.EXEC", Some(Exec), "write", 1),
+             ("REDISQL.EXEC_SCRIPT", Some(ExecScript), "write", 1),
+             ("REDISQL.APPLY_EFFECTS", Some(ApplyEffects), "write", 1),
+             ("REDISQL.EXEC_BATCH", Some(ExecBatch), "write", 1),
+             ("REDISQL.IMPORT_CSV", Some(ImportCsv), "write", 1),
+             ("REDISQL.QUERY", Some(Query), "readonly", 1),
+             ("REDISQL.QUERY_CURSOR", Some(QueryCursor), "readonly", 1),
+             ("REDISQL.FETCH", Some(Fetch), "readonly", 1),
+             ("REDISQL.CLOSE_CURSOR", Some(CloseCursor), "readonly", 1),
+             ("REDISQL.CREATE_STATEMENT", Some(CreateStatement), "write", 1),
+             ("REDISQL.EXEC_STATEMENT", Some(ExecStatement), "write", 1),
+             ("REDISQL.UPDATE_STATEMENT", Some(UpdateStatement), "write", 1),
+             ("REDISQL.DELETE_STATEMENT", Some(DeleteStatement), "write", 1),
+             ("REDISQL.BEGIN", Some(Begin), "write", 1),
+             ("REDISQL.COMMIT", Some(Commit), "write", 1),
+             ("REDISQL.ROLLBACK", Some(Rollback), "write", 1),
+             ("REDISQL.PROCESSLIST", Some(ProcessList), "readonly", 0),
+             ("REDISQL.KILL", Some(Kill), "readonly", 0)];
 
-    let command_c_name = CString::new("REDISQL.CREATE_DB").unwrap();
-    let command_ptr_name = command_c_name.as_ptr();
+    for (name, command, flags, key) in commands {
+        if create_command(ctx, name, command, flags, key, key, key) ==
+           ffi::REDISMODULE_ERR {
+            log_warning(&format!("Error in CreateCommand {}", name));
+            return ffi::REDISMODULE_ERR;
+        }
+    }
+    ffi::REDISMODULE_OK
+}
 
-    let flag_c_name = CString::new("write").unwrap();
-    let flag_ptr_name = flag_c_name.as_ptr();
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix;
+    use std::process;
+    use std::sync::atomic::Ordering;
+    use super::{CsvRecord, Cursor, Database, Entity, FILE_DIRECTORY,
+                Parameter, QueryOptions, REPLICATE_EFFECTS, RawConnection,
+                apply_effects, begin_transaction, changes_rows, confined_path,
+                create_database, create_statement, dump_database, exec_batch,
+                exec_query, execute_statement, first_keyword,
+                infer_column_type, is_read_only, open_connection, parse_csv,
+                parse_parameter, relative_path, result_columns, take_effects};
 
-    if unsafe {
-        ffi::RedisModule_CreateCommand.unwrap()(ctx,
-                                                command_ptr_name,
-                                                create_db,
-                                                flag_ptr_name,
-                                                0,
-                                                0,
-                                                0)
-    } == ffi::REDISMODULE_ERR {
-        println!("Error in CreateCommand");
-        return ffi::REDISMODULE_ERR;
+    fn record(fields: &[Option<&str>]) -> CsvRecord {
+        fields.iter().map(|f| f.map(|f| f.as_bytes().to_vec())).collect()
+    }
+
+    fn memory_database() -> Database {
+        create_database(open_connection(String::from(":memory:")).unwrap())
+    }
+
+    fn exec(db: &Database, sql: &str) {
+        exec_query(db, String::from(sql), &[], QueryOptions::default())
+            .unwrap();
+    }
+
+    fn dump(conn: &RawConnection) -> Vec<String> {
+        let mut statements = Vec::new();
+        dump_database(conn, |sql| statements.push(String::from(sql))).unwrap();
+        statements
     }
 
+    // Replay the dump on a new database, as the AOF does.
+    fn replay(db: &Database) -> Database {
+        let copy = memory_database();
+        for sql in dump(&db.connection) {
+            exec(&copy, &sql);
+        }
+        assert_eq!(dump(&db.connection), dump(&copy.connection));
+        copy
+    }
 
-    let remove_db: ffi::RedisModuleCmdFunc = Some(DeleteDB);
+    // Every value the query returns, with its type.
+    fn select(db: &Database, sql: &str) -> Vec<String> {
+        let stmt = create_statement(&db.connection, String::from(sql))
+            .unwrap();
+        let cursor = execute_statement(&stmt).unwrap();
+        if let Cursor::RowsCursor { .. } = cursor {
+            cursor.flat_map(|row| row)
+                .map(|entity| match entity {
+                    Entity::Integer { int } => format!("integer {}", int),
+                    Entity::Float { float } => format!("float {:?}", float),
+                    Entity::Text { text } => format!("text {:?}", text),
+                    Entity::Blob { blob } => format!("blob {:?}", blob),
+                    _ => String::from("null"),
+                })
+                .collect()
+        } else {
+            vec![]
+        }
+    }
 
-    let command_c_name = CString::new("REDISQL.Delete_DB").unwrap();
-    let command_ptr_name = command_c_name.as_ptr();
+    #[test]
+    fn dump_keeps_every_value() {
+        let db = memory_database();
+        exec(&db, "CREATE TABLE t(v);");
+        exec(&db, "INSERT INTO t VALUES (0.1), (1.0 / 3), (3.0), (1e300), \
+                   (-2.5e-300), (9007199254740993), (NULL), ('it''s'), \
+                   (X''), (X'00FF');");
+        exec_query(&db,
+                   String::from("INSERT INTO t VALUES (?);"),
+                   &[Parameter::Text { text: b"a\0b".to_vec() }],
+                   QueryOptions::default())
+            .unwrap();
+        let copy = replay(&db);
+        let all = "SELECT rowid, v FROM t ORDER BY rowid;";
+        assert_eq!(select(&db, all), select(&copy, all));
+        assert!(select(&copy, all).contains(&String::from("float 3.0")));
+        assert!(select(&copy, all)
+            .contains(&String::from("text [97, 0, 98]")));
+    }
 
-    let flag_c_name = CString::new("write").unwrap();
-    let flag_ptr_name = flag_c_name.as_ptr();
+    #[test]
+    fn dump_keeps_the_schema() {
+        let db = memory_database();
+        exec(&db, "CREATE TABLE k(k TEXT PRIMARY KEY, v) WITHOUT ROWID;");
+        exec(&db, "INSERT INTO k VALUES ('b', 2), ('a', 1);");
+        exec(&db, "CREATE TABLE s(id INTEGER PRIMARY KEY AUTOINCREMENT, v);");
+        exec(&db, "INSERT INTO s(v) VALUES ('x'), ('y'), ('z');");
+        exec(&db, "DELETE FROM s WHERE id = 3;");
+        exec(&db, "CREATE TABLE log(v);");
+        exec(&db, "CREATE TRIGGER logged AFTER INSERT ON s BEGIN \
+                   INSERT INTO log VALUES (new.v); END;");
+        exec(&db, "INSERT INTO s(v) VALUES ('w');");
+        exec(&db, "CREATE INDEX by_v ON s(v);");
+        let copy = replay(&db);
+        // The rows are inserted back before the triggers are created.
+        let log = "SELECT v FROM log;";
+        assert_eq!(select(&copy, log), vec![String::from("text [119]")]);
+        let k = "SELECT k, v FROM k;";
+        assert_eq!(select(&db, k), select(&copy, k));
+        // The sequence goes on where it was, the trigger still fires.
+        exec(&db, "INSERT INTO s(v) VALUES ('v');");
+        exec(&copy, "INSERT INTO s(v) VALUES ('v');");
+        let s = "SELECT id, v FROM s ORDER BY id;";
+        assert_eq!(select(&db, s), select(&copy, s));
+        assert_eq!(select(&db, log), select(&copy, log));
+    }
 
-    if unsafe {
-        ffi::RedisModule_CreateCommand.unwrap()(ctx,
-                                                command_ptr_name,
-                                                remove_db,
-                                                flag_ptr_name,
-                                                0,
-                                                0,
-                                                0)
-    } == ffi::REDISMODULE_ERR {
-        println!("Error in CreateCommand");
-        return ffi::REDISMODULE_ERR;
+    #[test]
+    fn first_keyword_skips_the_comments() {
+        let db = memory_database();
+        let keyword = |sql: &str| {
+            let stmt = create_statement(&db.connection, String::from(sql))
+                .unwrap();
+            first_keyword(&stmt)
+        };
+        assert_eq!(keyword("select 1;"), "SELECT");
+        assert_eq!(keyword("  \n\tSelect 1;"), "SELECT");
+        assert_eq!(keyword("-- a comment\nSELECT 1;"), "SELECT");
+        assert_eq!(keyword("/* a\ncomment */ /**/ SELECT 1;"), "SELECT");
+        assert_eq!(keyword("-- one\n /* two */\n-- three\nbegin;"),
+                   "BEGIN");
+        assert_eq!(keyword("WITH x AS (SELECT 1) SELECT * FROM x;"), "WITH");
+    }
+
+    #[test]
+    fn transactions_and_attach_are_not_read_only() {
+        let db = memory_database();
+        exec(&db, "CREATE TABLE t(a);");
+        let read_only = |sql: &str| {
+            let stmt = create_statement(&db.connection, String::from(sql))
+                .unwrap();
+            is_read_only(&stmt)
+        };
+        assert!(read_only("SELECT * FROM t;"));
+        assert!(read_only("/* BEGIN */ SELECT 1;"));
+        assert!(read_only("WITH x AS (SELECT 1) SELECT * FROM x;"));
+        assert!(read_only("PRAGMA table_info(t);"));
+        assert!(!read_only("BEGIN;"));
+        assert!(!read_only("-- comment\nbegin immediate;"));
+        assert!(!read_only("COMMIT;"));
+        assert!(!read_only("END;"));
+        assert!(!read_only("ROLLBACK;"));
+        assert!(!read_only("SAVEPOINT s;"));
+        assert!(!read_only("RELEASE s;"));
+        assert!(!read_only("ATTACH ':memory:' AS other;"));
+        assert!(!read_only("DETACH other;"));
+        assert!(!read_only("INSERT INTO t VALUES (1);"));
+        assert!(!read_only("CREATE TABLE u(a);"));
+    }
+
+    #[test]
+    fn only_dml_changes_rows() {
+        let db = memory_database();
+        exec(&db, "CREATE TABLE t(a PRIMARY KEY);");
+        let changes = |sql: &str| {
+            let stmt = create_statement(&db.connection, String::from(sql))
+                .unwrap();
+            changes_rows(&stmt)
+        };
+        assert!(changes("INSERT INTO t VALUES (1);"));
+        assert!(changes("/* comment */ insert into t values (1);"));
+        assert!(changes("UPDATE t SET a = 2;"));
+        assert!(changes("DELETE FROM t;"));
+        assert!(changes("REPLACE INTO t VALUES (1);"));
+        assert!(changes("WITH x AS (SELECT 1) \
+                         INSERT INTO t SELECT * FROM x;"));
+        assert!(!changes("SELECT * FROM t;"));
+        assert!(!changes("WITH x AS (SELECT 1) SELECT * FROM x;"));
+        assert!(!changes("CREATE TABLE u(a);"));
+        assert!(!changes("CREATE INDEX i ON t(a);"));
+        assert!(!changes("DROP TABLE t;"));
+        assert!(!changes("BEGIN;"));
+    }
+
+    #[test]
+    fn parameters_are_inferred() {
+        match parse_parameter(b"42") {
+            Ok(Parameter::Integer { int: 42 }) => {}
+            _ => panic!("42 should be an integer"),
+        }
+        match parse_parameter(b"-3.5") {
+            Ok(Parameter::Float { float }) => assert_eq!(float, -3.5),
+            _ => panic!("-3.5 should be a float"),
+        }
+        match parse_parameter(b"1.0") {
+            Ok(Parameter::Float { float }) => assert_eq!(float, 1.0),
+            _ => panic!("1.0 should be a float"),
+        }
+        match parse_parameter(b"inf") {
+            Ok(Parameter::Text { ref text }) if text == b"inf" => {}
+            _ => panic!("inf should be a text"),
+        }
+        for number in &[&b"007"[..], b"+5", b"1.", b" 1", b"1e3", b"0.50"] {
+            match parse_parameter(number) {
+                Ok(Parameter::Text { ref text }) if text == number => {}
+                _ => {
+                    panic!("{} should be a text",
+                           String::from_utf8_lossy(number))
+                }
+            }
+        }
+        match parse_parameter(b"hello:world") {
+            Ok(Parameter::Text { ref text }) if text == b"hello:world" => {}
+            _ => panic!("an unknown marker should be part of the text"),
+        }
+        match parse_parameter(b"\xff\xfe") {
+            Ok(Parameter::Text { ref text }) if text == b"\xff\xfe" => {}
+            _ => panic!("invalid UTF-8 should be kept as it is"),
+        }
     }
 
+    #[test]
+    fn markers_force_the_type() {
+        match parse_parameter(b"TEXT:42") {
+            Ok(Parameter::Text { ref text }) if text == b"42" => {}
+            _ => panic!("TEXT:42 should be a text"),
+        }
+        match parse_parameter(b"INTEGER:7") {
+            Ok(Parameter::Integer { int: 7 }) => {}
+            _ => panic!("INTEGER:7 should be an integer"),
+        }
+        match parse_parameter(b"FLOAT:1") {
+            Ok(Parameter::Float { float }) => assert_eq!(float, 1.0),
+            _ => panic!("FLOAT:1 should be a float"),
+        }
+        match parse_parameter(b"BLOB:\x00\x01") {
+            Ok(Parameter::Blob { ref blob }) if blob == b"\x00\x01" => {}
+            _ => panic!("BLOB: should keep the bytes"),
+        }
+        match parse_parameter(b"NULL:") {
+            Ok(Parameter::Null) => {}
+            _ => panic!("NULL: should be null"),
+        }
+    }
 
+    #[test]
+    fn invalid_values_after_a_marker_are_errors() {
+        assert!(parse_parameter(b"INTEGER:abc").is_err());
+        assert!(parse_parameter(b"INTEGER:1.5").is_err());
+        assert!(parse_parameter(b"INTEGER:").is_err());
+        assert!(parse_parameter(b"FLOAT:x").is_err());
+        assert!(parse_parameter(b"FLOAT:").is_err());
+        assert!(parse_parameter(b"NULL:x").is_err());
+    }
 
+    #[test]
+    fn csv_fields_can_be_quoted() {
+        let records = parse_csv(b"\"a,b\",\"say \"\"hi\"\"\",\"\",\n", b',')
+            .unwrap();
+        assert!(records == vec![record(&[Some("a,b"),
+                                         Some("say \"hi\""),
+                                         Some(""),
+                                         None])]);
+        let records = parse_csv(b"\"two\nlines\";x", b';').unwrap();
+        assert!(records == vec![record(&[Some("two\nlines"), Some("x")])]);
+        assert!(parse_csv(b"a,\"unterminated\n", b',').is_err());
+    }
 
+    #[test]
+    fn csv_records_end_with_crlf_or_lf() {
+        let records = parse_csv(b"a,b\r\nc,d\re,f\ng,h", b',').unwrap();
+        assert!(records == vec![record(&[Some("a"), Some("b")]),
+                                record(&[Some("c"), Some("d")]),
+                                record(&[Some("e"), Some("f")]),
+                                record(&[Some("g"), Some("h")])]);
+    }
 
-    let exec: ffi::RedisModuleCmdFunc = Some(Exec);
+    #[test]
+    fn csv_blank_lines_are_skipped() {
+        let records = parse_csv(b"\na,b\n\r\n\nc,d\n\n", b',').unwrap();
+        assert!(records == vec![record(&[Some("a"), Some("b")]),
+                                record(&[Some("c"), Some("d")])]);
+        // A quoted empty field is not a blank line.
+        let records = parse_csv(b"\"\"\n", b',').unwrap();
+        assert!(records == vec![record(&[Some("")])]);
+        assert!(parse_csv(b"", b',').unwrap().is_empty());
+    }
 
-    let command_c_name = CString::new("REDISQL.EXEC").unwrap();
-    let command_ptr_name = command_c_name.as_ptr();
+    #[test]
+    fn csv_ragged_records_are_kept_as_they_are() {
+        let records = parse_csv(b"a,b,c\nd\ne,f\n", b',').unwrap();
+        let lengths: Vec<usize> = records.iter().map(|r| r.len()).collect();
+        assert_eq!(lengths, vec![3, 1, 2]);
+    }
 
-    let flag_c_name = CString::new("write").unwrap();
-    let flag_ptr_name = flag_c_name.as_ptr();
+    #[test]
+    fn csv_column_types_are_inferred() {
+        let records = vec![record(&[Some("1"), Some("1"), Some("1"), None]),
+                           record(&[Some("-2"), Some("2.5"), Some("x"), None]),
+                           record(&[None, Some("3"), Some("3"), None])];
+        assert_eq!(infer_column_type(&records, 0), "INTEGER");
+        assert_eq!(infer_column_type(&records, 1), "REAL");
+        assert_eq!(infer_column_type(&records, 2), "TEXT");
+        assert_eq!(infer_column_type(&records, 3), "TEXT");
+        // Past the end of the short records the values are NULL.
+        let ragged = vec![record(&[Some("1"), Some("2")]),
+                          record(&[Some("3")])];
+        assert_eq!(infer_column_type(&ragged, 1), "INTEGER");
+        assert_eq!(infer_column_type(&ragged, 5), "TEXT");
+    }
 
-    if unsafe {
-        ffi::RedisModule_CreateCommand.unwrap()(ctx,
-                                                command_ptr_name,
-                                                exec,
-                                                flag_ptr_name,
-                                                0,
-                                                0,
-                                                0)
-    } == ffi::REDISMODULE_ERR {
-        println!("Error in CreateCommand");
-        return ffi::REDISMODULE_ERR;
+    #[test]
+    fn effects_replay_on_another_database() {
+        REPLICATE_EFFECTS.store(true, Ordering::SeqCst);
+        let master = memory_database();
+        exec(&master, "CREATE TABLE t(a INTEGER PRIMARY KEY, b TEXT);");
+        exec(&master, "INSERT INTO t(b) VALUES ('x'), ('y'), ('z');");
+        exec(&master, "UPDATE t SET b = 'w' WHERE a = 1;");
+        exec(&master, "DELETE FROM t WHERE a = 2;");
+        exec(&master, "CREATE TABLE k(k TEXT PRIMARY KEY, v) WITHOUT ROWID;");
+        exec(&master, "INSERT INTO k VALUES ('a', 1.5);");
+        let script = take_effects(&master).unwrap();
+        assert!(take_effects(&master).is_none());
+
+        let replica = memory_database();
+        apply_effects(&replica, script).unwrap();
+        assert_eq!(dump(&master.connection), dump(&replica.connection));
+    }
+
+    #[test]
+    fn effects_do_not_fire_triggers_again() {
+        REPLICATE_EFFECTS.store(true, Ordering::SeqCst);
+        let master = memory_database();
+        exec(&master, "CREATE TABLE t(a INTEGER PRIMARY KEY, b TEXT);");
+        exec(&master, "CREATE TABLE log(a);");
+        exec(&master, "CREATE TRIGGER logged AFTER INSERT ON t BEGIN \
+                       INSERT INTO log VALUES (new.a); END;");
+        exec(&master, "CREATE TABLE c(p REFERENCES t(a) ON DELETE CASCADE);");
+        exec(&master, "INSERT INTO t VALUES (1, 'x');");
+        exec(&master, "UPDATE t SET b = 'y' WHERE a = 1;");
+        exec(&master, "PRAGMA foreign_keys = ON;");
+        exec(&master, "INSERT INTO c VALUES (1);");
+        exec(&master, "DELETE FROM t WHERE a = 1;");
+        let script = take_effects(&master).unwrap();
+
+        let replica = memory_database();
+        exec(&replica, "PRAGMA foreign_keys = ON;");
+        apply_effects(&replica, script).unwrap();
+        assert_eq!(dump(&master.connection), dump(&replica.connection));
+    }
+
+    #[test]
+    fn a_failed_batch_leaves_no_effects() {
+        REPLICATE_EFFECTS.store(true, Ordering::SeqCst);
+        let db = memory_database();
+        exec(&db, "CREATE TABLE t(a INTEGER PRIMARY KEY);");
+        take_effects(&db);
+        let rows = vec![Parameter::Integer { int: 1 },
+                        Parameter::Integer { int: 2 },
+                        Parameter::Integer { int: 1 }];
+        assert!(exec_batch(&db, String::from("INSERT INTO t VALUES (?);"), 1,
+                           &rows)
+            .is_err());
+        assert!(take_effects(&db).is_none());
+    }
+
+    #[test]
+    fn only_what_is_committed_is_saved() {
+        let mut db = memory_database();
+        exec(&db, "CREATE TABLE t(a);");
+        exec(&db, "INSERT INTO t VALUES (1);");
+        let before = dump(&db.connection);
+        begin_transaction(&mut db, 1).unwrap();
+        exec(&db, "INSERT INTO t VALUES (2);");
+        assert!(dump(&db.connection) != before);
+        assert_eq!(dump(db.committed_connection()), before);
+    }
+
+    #[test]
+    fn column_names_are_unique() {
+        let conn = open_connection(String::from(":memory:")).unwrap();
+        let stmt = create_statement(&conn,
+                                    String::from("SELECT 1 AS a, 2 AS a, \
+                                                  3 AS \"a:1\", 4 AS b;"))
+            .unwrap();
+        let names: Vec<Vec<u8>> = result_columns(&stmt)
+            .into_iter()
+            .map(|column| column.name)
+            .collect();
+        assert_eq!(names,
+                   vec![b"a".to_vec(),
+                        b"a:2".to_vec(),
+                        b"a:1".to_vec(),
+                        b"b".to_vec()]);
+    }
+
+    #[test]
+    fn files_stay_in_the_file_directory() {
+        let directory = env::temp_dir()
+            .join(format!("redisql-files-{}", process::id()));
+        fs::create_dir_all(directory.join("csv")).unwrap();
+        unix::fs::symlink("/etc", directory.join("etc")).unwrap();
+        *FILE_DIRECTORY.lock().unwrap() =
+            Some(fs::canonicalize(&directory).unwrap());
+        assert!(confined_path("data.csv").is_ok());
+        assert!(confined_path("csv/data.csv").is_ok());
+        assert!(confined_path("csv/../data.csv").is_ok());
+        assert!(confined_path("../data.csv").is_err());
+        assert!(confined_path("csv/../../data.csv").is_err());
+        assert!(confined_path("/etc/passwd").is_err());
+        assert!(confined_path("etc/passwd").is_err());
+        assert!(confined_path("missing/data.csv").is_err());
+        let file = confined_path("csv/./data.csv").unwrap();
+        assert_eq!(relative_path(&file), "csv/data.csv");
+        assert_eq!(relative_path(&confined_path(&file).unwrap()),
+                   "csv/data.csv");
+        fs::remove_dir_all(&directory).unwrap();
     }
-    ffi::REDISMODULE_OK
 }